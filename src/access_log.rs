@@ -0,0 +1,296 @@
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{LineWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Mutex, OnceLock, PoisonError,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::config::common::access_log::AccessLogFormat;
+
+/// How many entries can wait for the writer thread before new ones are dropped
+const QUEUE_SIZE: usize = 8192;
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    /// Handed to the writer thread when the first entry is logged
+    writer: Mutex<Option<Box<dyn Write + Send>>>,
+    /// Entries are written on a separate thread so request handling never blocks on IO.
+    ///
+    /// The thread is started lazily because threads don't survive daemonizing.
+    sender: OnceLock<SyncSender<String>>,
+    dropped: AtomicU64,
+}
+impl AccessLog {
+    pub fn new(format: AccessLogFormat, path: &Path) -> std::io::Result<Self> {
+        let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+            Box::new(LineWriter::new(file))
+        };
+
+        Ok(Self {
+            format,
+            writer: Mutex::new(Some(writer)),
+            sender: OnceLock::new(),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let mut line = match self.format {
+            AccessLogFormat::Json => match serde_json::to_string(entry) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!(?e, "Failed to serialize access log entry");
+                    return;
+                }
+            },
+            AccessLogFormat::Combined => entry.to_combined(),
+            AccessLogFormat::Logfmt => entry.to_logfmt(),
+        };
+        line.push('\n');
+
+        let sender = self.sender.get_or_init(|| self.spawn_writer());
+
+        match sender.try_send(line) {
+            Ok(()) => {
+                let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!(dropped, "Dropped access log entries because the writer fell behind");
+                }
+            }
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    fn spawn_writer(&self) -> SyncSender<String> {
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        if let Some(mut writer) = writer {
+            let spawned = std::thread::Builder::new()
+                .name("access-log".to_string())
+                .spawn(move || {
+                    for line in receiver {
+                        if let Err(e) = writer.write_all(line.as_bytes()) {
+                            warn!(?e, "Failed to write access log entry");
+                        }
+                    }
+                });
+
+            if let Err(e) = spawned {
+                warn!(?e, "Failed to start access log writer");
+            }
+        }
+
+        sender
+    }
+}
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry<'a> {
+    #[serde(serialize_with = "serialize_time")]
+    pub time: DateTime<Utc>,
+    pub request_id: &'a str,
+    pub client: Option<IpAddr>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub protocol: &'a str,
+    pub host: Option<&'a str>,
+    pub origin: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub status: u16,
+    /// Request body size as declared in the `Content-Length` header
+    pub bytes_in: Option<u64>,
+    pub bytes_out: usize,
+    pub upstream: Option<SocketAddr>,
+    #[serde(serialize_with = "serialize_opt_millis")]
+    pub upstream_time: Option<Duration>,
+    #[serde(serialize_with = "serialize_millis")]
+    pub duration: Duration,
+    pub cors: bool,
+}
+impl AccessLogEntry<'_> {
+    fn to_combined(&self) -> String {
+        format!(
+            r#"{client} - - [{time}] "{method} {path} {protocol}" {status} {bytes_out} "{referer}" "{user_agent}""#,
+            client = self
+                .client
+                .map_or_else(|| "-".to_string(), |x| x.to_string()),
+            time = self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            method = self.method,
+            path = escape_quoted(self.path),
+            protocol = self.protocol,
+            status = self.status,
+            bytes_out = self.bytes_out,
+            referer = escape_quoted(self.referer.unwrap_or("-")),
+            user_agent = escape_quoted(self.user_agent.unwrap_or("-")),
+        )
+    }
+
+    fn to_logfmt(&self) -> String {
+        let fields: [(&str, Option<String>); 17] = [
+            (
+                "time",
+                Some(self.time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            ),
+            ("request_id", Some(self.request_id.to_string())),
+            ("client", self.client.map(|x| x.to_string())),
+            ("method", Some(self.method.to_string())),
+            ("path", Some(self.path.to_string())),
+            ("protocol", Some(self.protocol.to_string())),
+            ("host", self.host.map(str::to_string)),
+            ("origin", self.origin.map(str::to_string)),
+            ("referer", self.referer.map(str::to_string)),
+            ("user_agent", self.user_agent.map(str::to_string)),
+            ("status", Some(self.status.to_string())),
+            ("bytes_in", self.bytes_in.map(|x| x.to_string())),
+            ("bytes_out", Some(self.bytes_out.to_string())),
+            ("upstream", self.upstream.map(|x| x.to_string())),
+            ("upstream_time", self.upstream_time.map(format_millis)),
+            ("duration", Some(format_millis(self.duration))),
+            ("cors", Some(self.cors.to_string())),
+        ];
+
+        let mut line = String::new();
+        for (key, value) in fields {
+            let Some(value) = value else {
+                continue;
+            };
+
+            if !line.is_empty() {
+                line.push(' ');
+            }
+
+            if value.is_empty()
+                || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=')
+            {
+                let _ = write!(line, r#"{key}="{}""#, escape_quoted(&value));
+            } else {
+                let _ = write!(line, "{key}={value}");
+            }
+        }
+
+        line
+    }
+}
+
+fn escape_quoted(s: &str) -> String {
+    s.replace('\\', r"\\").replace('"', r#"\""#)
+}
+
+fn format_millis(d: Duration) -> String {
+    format!("{:.3}", d.as_secs_f64() * 1000.0)
+}
+
+fn serialize_time<S: serde::Serializer>(t: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&t.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+fn serialize_millis<S: serde::Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64() * 1000.0)
+}
+
+#[allow(clippy::ref_option)]
+fn serialize_opt_millis<S: serde::Serializer>(
+    d: &Option<Duration>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => serialize_millis(d, s),
+        None => s.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry<'a>(path: &'a str, user_agent: Option<&'a str>) -> AccessLogEntry<'a> {
+        AccessLogEntry {
+            time: DateTime::from_timestamp(1_700_000_000, 0).expect("valid timestamp"),
+            request_id: "abc",
+            client: Some("192.0.2.1".parse().expect("valid address")),
+            method: "GET",
+            path,
+            protocol: "HTTP/1.1",
+            host: Some("example.com"),
+            origin: None,
+            referer: None,
+            user_agent,
+            status: 200,
+            bytes_in: None,
+            bytes_out: 5,
+            upstream: None,
+            upstream_time: None,
+            duration: Duration::from_micros(1500),
+            cors: true,
+        }
+    }
+
+    #[test]
+    fn combined() {
+        assert_eq!(
+            entry("/a", Some("curl/8.0")).to_combined(),
+            r#"192.0.2.1 - - [14/Nov/2023:22:13:20 +0000] "GET /a HTTP/1.1" 200 5 "-" "curl/8.0""#
+        );
+    }
+
+    #[test]
+    fn combined_escapes_quotes_and_backslashes() {
+        let line = entry(r#"/a"b\c"#, Some(r#"x" "y"#)).to_combined();
+
+        assert!(line.contains(r#""GET /a\"b\\c HTTP/1.1""#));
+        assert!(line.ends_with(r#""-" "x\" \"y""#));
+    }
+
+    #[test]
+    fn logfmt() {
+        assert_eq!(
+            entry("/a", None).to_logfmt(),
+            "time=2023-11-14T22:13:20.000Z request_id=abc client=192.0.2.1 method=GET path=/a \
+             protocol=HTTP/1.1 host=example.com status=200 bytes_out=5 duration=1.500 cors=true"
+        );
+    }
+
+    #[test]
+    fn logfmt_quotes_values_that_need_it() {
+        let line = entry("/a=b", Some(r#"Mozilla/5.0 "x" \"#)).to_logfmt();
+
+        assert!(line.contains(r#" path="/a=b" "#));
+        assert!(line.contains(r#" user_agent="Mozilla/5.0 \"x\" \\" "#));
+    }
+
+    #[test]
+    fn logfmt_quotes_empty_values() {
+        assert!(entry("", None).to_logfmt().contains(r#" path="" "#));
+    }
+}
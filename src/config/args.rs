@@ -1,6 +1,9 @@
 use clap::ArgAction;
 
-use super::common::{pingora::PingoraConfig, proxy::ProxyArgs, server::ServerConfig};
+use super::common::{
+    access_log::AccessLogConfig, metrics::MetricsConfig, pingora::PingoraConfig, proxy::ProxyArgs,
+    server::ServerConfig, telemetry::TelemetryConfig,
+};
 
 #[derive(Debug, clap::Parser)]
 #[clap(disable_help_flag = true)]
@@ -17,4 +20,13 @@ pub(super) struct Args {
 
     #[clap(flatten)]
     pub proxy: ProxyArgs,
+
+    #[clap(flatten)]
+    pub metrics: MetricsConfig,
+
+    #[clap(flatten)]
+    pub telemetry: TelemetryConfig,
+
+    #[clap(flatten)]
+    pub access_log: AccessLogConfig,
 }
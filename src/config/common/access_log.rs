@@ -0,0 +1,34 @@
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AccessLogFormat {
+    /// One JSON object per line
+    Json,
+    /// The Apache/NGINX combined log format
+    Combined,
+    /// `key=value` pairs separated by spaces
+    Logfmt,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Access log options")]
+#[allow(clippy::struct_field_names)]
+pub struct AccessLogConfig {
+    /// Write an access log entry for every request in the given format.
+    ///
+    /// The access log is disabled if this is not set.
+    #[arg(long, value_enum, env = "CORS_PROXY_ACCESS_LOG_FORMAT")]
+    pub access_log_format: Option<AccessLogFormat>,
+
+    /// Where to write the access log.
+    ///
+    /// Entries are appended to the file if it already exists.
+    /// Use `-` to write to stdout, which is shared with the proxy's own logs.
+    #[arg(
+        long,
+        value_name = "FILE_PATH",
+        default_value = "-",
+        env = "CORS_PROXY_ACCESS_LOG_FILE"
+    )]
+    pub access_log_file: PathBuf,
+}
@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use super::{size::ByteSize, timeframe::Timeframe};
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Cache options")]
+#[allow(clippy::struct_field_names)]
+pub struct CacheArgs {
+    /// Cache responses to `GET` requests in memory.
+    ///
+    /// Responses are cached for as long as their `Cache-Control` or `Expires` headers allow,
+    /// and stale ones are revalidated with their `ETag` or `Last-Modified` headers.
+    /// Responses without either header aren't cached.
+    /// Nothing is written to disk, so the cache is empty after a restart.
+    #[clap(long, env = "CORS_PROXY_CACHE")]
+    pub cache: bool,
+
+    /// How much memory cached responses may use before the least recently used ones are evicted.
+    ///
+    /// Eg. `512KiB` or `64MiB`
+    #[clap(
+        long,
+        value_name = "SIZE",
+        default_value = "64MiB",
+        value_parser = ByteSize::parse_str,
+        env = "CORS_PROXY_CACHE_SIZE"
+    )]
+    pub cache_size: ByteSize,
+
+    /// Responses with a larger body aren't cached.
+    ///
+    /// Eg. `512KiB` or `1MiB`
+    #[clap(
+        long,
+        value_name = "SIZE",
+        default_value = "1MiB",
+        value_parser = ByteSize::parse_str,
+        env = "CORS_PROXY_CACHE_MAX_RESPONSE_SIZE"
+    )]
+    pub cache_max_response_size: ByteSize,
+
+    /// How long requests for a response that another request is already fetching
+    /// wait for it to be cached, before going to the upstream themselves.
+    ///
+    /// `0s` sends every request that misses the cache to the upstream.
+    ///
+    /// Eg. `5s` or `1m`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        default_value = "10s",
+        env = "CORS_PROXY_CACHE_LOCK_TIMEOUT"
+    )]
+    pub cache_lock_timeout: Timeframe,
+}
+impl CacheArgs {
+    pub fn to_config(&self) -> Option<CacheConfig> {
+        if !self.cache {
+            return None;
+        }
+
+        Some(CacheConfig {
+            size: self.cache_size.into(),
+            max_response_size: self.cache_max_response_size.into(),
+            lock_timeout: Some(self.cache_lock_timeout.into()).filter(|x: &Duration| !x.is_zero()),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub size: usize,
+
+    pub max_response_size: usize,
+
+    pub lock_timeout: Option<Duration>,
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use super::timeframe::Timeframe;
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Circuit breaker options")]
+#[allow(clippy::struct_field_names)]
+pub struct CircuitBreakerArgs {
+    /// Stop sending requests to an upstream address that keeps failing, answering with a
+    /// `503 Service Unavailable` right away instead, until `--circuit-breaker-open-duration` passes.
+    ///
+    /// Failed connections and `5xx` responses count as failures.
+    #[clap(long, env = "CORS_PROXY_CIRCUIT_BREAKER")]
+    pub circuit_breaker: bool,
+
+    /// Open the circuit after this many failures in a row.
+    #[clap(
+        long,
+        value_name = "FAILURES",
+        default_value = "5",
+        env = "CORS_PROXY_CIRCUIT_BREAKER_FAILURES"
+    )]
+    pub circuit_breaker_failures: u32,
+
+    /// Open the circuit when this fraction of the requests in a `--circuit-breaker-window` failed,
+    /// eg. `0.5` for half of them.
+    #[clap(
+        long,
+        value_name = "RATIO",
+        default_value = "0.5",
+        env = "CORS_PROXY_CIRCUIT_BREAKER_ERROR_RATE"
+    )]
+    pub circuit_breaker_error_rate: f64,
+
+    /// How many requests there must be in a window before the error rate is considered.
+    #[clap(
+        long,
+        value_name = "REQUESTS",
+        default_value = "20",
+        env = "CORS_PROXY_CIRCUIT_BREAKER_MIN_REQUESTS"
+    )]
+    pub circuit_breaker_min_requests: u32,
+
+    /// The window the error rate is measured over.
+    ///
+    /// Eg. `10s` or `1m`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        default_value = "10s",
+        env = "CORS_PROXY_CIRCUIT_BREAKER_WINDOW"
+    )]
+    pub circuit_breaker_window: Timeframe,
+
+    /// How long the circuit stays open before trial requests are let through again.
+    ///
+    /// Eg. `10s` or `1m`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        default_value = "30s",
+        env = "CORS_PROXY_CIRCUIT_BREAKER_OPEN_DURATION"
+    )]
+    pub circuit_breaker_open_duration: Timeframe,
+
+    /// How many trial requests must succeed, one at a time, to close the circuit again.
+    #[clap(
+        long,
+        value_name = "REQUESTS",
+        default_value = "1",
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "CORS_PROXY_CIRCUIT_BREAKER_TRIAL_REQUESTS"
+    )]
+    pub circuit_breaker_trial_requests: u32,
+}
+impl CircuitBreakerArgs {
+    pub fn to_config(&self) -> Option<CircuitBreakerConfig> {
+        if !self.circuit_breaker {
+            return None;
+        }
+
+        Some(CircuitBreakerConfig {
+            failures: self.circuit_breaker_failures.max(1),
+            error_rate: self.circuit_breaker_error_rate,
+            min_requests: self.circuit_breaker_min_requests.max(1),
+            window: self.circuit_breaker_window.into(),
+            open_duration: self.circuit_breaker_open_duration.into(),
+            trial_requests: self.circuit_breaker_trial_requests,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures that open the circuit
+    pub failures: u32,
+
+    pub error_rate: f64,
+
+    pub min_requests: u32,
+
+    pub window: Duration,
+
+    pub open_duration: Duration,
+
+    /// Successful trial requests that close the circuit
+    pub trial_requests: u32,
+}
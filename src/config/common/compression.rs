@@ -0,0 +1,248 @@
+use super::size::ByteSize;
+
+/// A content encoding the proxy can compress responses with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+impl Encoding {
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "gzip" => Ok(Self::Gzip),
+            "br" | "brotli" => Ok(Self::Brotli),
+            "zstd" => Ok(Self::Zstd),
+            x => Err(format!(
+                "Unsupported compression algorithm {x:?} (expected gzip, br or zstd)"
+            )),
+        }
+    }
+
+    /// The name used in `Accept-Encoding` and `Content-Encoding`
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Compression options")]
+pub struct CompressionArgs {
+    /// Compress responses for clients that accept one of `--compression-algorithms`.
+    ///
+    /// Responses the upstream already compressed are passed through as they are.
+    #[clap(long, env = "CORS_PROXY_COMPRESSION")]
+    pub compression: bool,
+
+    /// The algorithms to compress with, in order of preference.
+    ///
+    /// For example, `gzip` or `zstd,br,gzip`
+    #[clap(
+        long,
+        value_name = "ALGORITHM",
+        value_delimiter = ',',
+        default_value = "zstd,br,gzip",
+        value_parser = Encoding::parse_str,
+        env = "CORS_PROXY_COMPRESSION_ALGORITHMS"
+    )]
+    pub compression_algorithms: Vec<Encoding>,
+
+    /// The gzip compression level, from 1 to 9.
+    #[clap(
+        long,
+        value_name = "LEVEL",
+        default_value = "6",
+        value_parser = clap::value_parser!(u32).range(1..=9),
+        env = "CORS_PROXY_GZIP_LEVEL"
+    )]
+    pub gzip_level: u32,
+
+    /// The Brotli compression level, from 1 to 11.
+    #[clap(
+        long,
+        value_name = "LEVEL",
+        default_value = "4",
+        value_parser = clap::value_parser!(u32).range(1..=11),
+        env = "CORS_PROXY_BROTLI_LEVEL"
+    )]
+    pub brotli_level: u32,
+
+    /// The zstd compression level, from 1 to 22.
+    #[clap(
+        long,
+        value_name = "LEVEL",
+        default_value = "3",
+        value_parser = clap::value_parser!(u32).range(1..=22),
+        env = "CORS_PROXY_ZSTD_LEVEL"
+    )]
+    pub zstd_level: u32,
+
+    /// Responses smaller than this aren't compressed.
+    ///
+    /// Responses without a `Content-Length` are always compressed.
+    ///
+    /// Eg. `256B` or `1KiB`
+    #[clap(
+        long,
+        value_name = "SIZE",
+        default_value = "1KiB",
+        value_parser = ByteSize::parse_str,
+        env = "CORS_PROXY_COMPRESSION_MIN_SIZE"
+    )]
+    pub compression_min_size: ByteSize,
+
+    /// The content types to compress, either exactly or as `type/*`.
+    ///
+    /// For example, `text/*` or `text/*,application/json`
+    #[clap(
+        long,
+        value_name = "TYPE",
+        value_delimiter = ',',
+        default_value = "text/*,application/json,application/javascript,application/xml,image/svg+xml",
+        env = "CORS_PROXY_COMPRESSION_CONTENT_TYPES"
+    )]
+    pub compression_content_types: Vec<String>,
+
+    /// Decompress Brotli responses for clients that don't accept Brotli.
+    ///
+    /// Pingora can only decompress Brotli, so responses in other encodings
+    /// are still passed through as they are. Works with or without `--compression`.
+    #[clap(long, env = "CORS_PROXY_DECOMPRESSION")]
+    pub decompression: bool,
+}
+impl CompressionArgs {
+    pub fn to_config(&self) -> Option<CompressionConfig> {
+        if !self.compression {
+            return None;
+        }
+
+        Some(CompressionConfig {
+            algorithms: self.compression_algorithms.clone(),
+            gzip_level: self.gzip_level,
+            brotli_level: self.brotli_level,
+            zstd_level: self.zstd_level,
+            min_size: self.compression_min_size.into(),
+            content_types: self
+                .compression_content_types
+                .iter()
+                .map(|x| x.trim().to_lowercase())
+                .filter(|x| !x.is_empty())
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    pub algorithms: Vec<Encoding>,
+
+    pub gzip_level: u32,
+
+    pub brotli_level: u32,
+
+    pub zstd_level: u32,
+
+    pub min_size: usize,
+
+    pub content_types: Vec<String>,
+}
+impl CompressionConfig {
+    pub const fn level(&self, encoding: Encoding) -> u32 {
+        match encoding {
+            Encoding::Gzip => self.gzip_level,
+            Encoding::Brotli => self.brotli_level,
+            Encoding::Zstd => self.zstd_level,
+        }
+    }
+
+    /// Whether responses with this `Content-Type` should be compressed
+    pub fn compresses_type(&self, content_type: &str) -> bool {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        self.content_types.iter().any(|x| {
+            x.strip_suffix('*')
+                .map_or_else(|| media_type == *x, |prefix| media_type.starts_with(prefix))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Debug, Parser)]
+    struct TestArgs {
+        #[clap(flatten)]
+        compression: CompressionArgs,
+    }
+
+    fn config(content_types: &[&str]) -> CompressionConfig {
+        CompressionConfig {
+            algorithms: vec![Encoding::Gzip],
+            gzip_level: 6,
+            brotli_level: 4,
+            zstd_level: 3,
+            min_size: 0,
+            content_types: content_types.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn matches_exact_types() {
+        let config = config(&["application/json"]);
+
+        assert!(config.compresses_type("application/json"));
+        assert!(config.compresses_type("Application/JSON"));
+        assert!(config.compresses_type("application/json; charset=utf-8"));
+        assert!(config.compresses_type(" application/json ;charset=utf-8"));
+
+        assert!(!config.compresses_type("application/json-seq"));
+        assert!(!config.compresses_type("application/xml"));
+        assert!(!config.compresses_type(""));
+    }
+
+    #[test]
+    fn matches_wildcard_types() {
+        let config = config(&["text/*"]);
+
+        assert!(config.compresses_type("text/html"));
+        assert!(config.compresses_type("text/plain; charset=utf-8"));
+
+        assert!(!config.compresses_type("application/text"));
+        assert!(!config.compresses_type("textual/html"));
+    }
+
+    #[test]
+    fn parses_encodings() {
+        assert_eq!(Encoding::parse_str("gzip"), Ok(Encoding::Gzip));
+        assert_eq!(Encoding::parse_str(" BR "), Ok(Encoding::Brotli));
+        assert_eq!(Encoding::parse_str("brotli"), Ok(Encoding::Brotli));
+        assert_eq!(Encoding::parse_str("zstd"), Ok(Encoding::Zstd));
+        assert!(Encoding::parse_str("deflate").is_err());
+    }
+
+    #[test]
+    fn default_content_types() {
+        let config = TestArgs::parse_from(["cors-proxy", "--compression"])
+            .compression
+            .to_config()
+            .expect("compression is enabled");
+
+        assert!(config.compresses_type("text/html"));
+        assert!(config.compresses_type("application/json"));
+        assert!(config.compresses_type("image/svg+xml"));
+        assert!(!config.compresses_type("image/png"));
+        assert!(!config.compresses_type("application/octet-stream"));
+    }
+}
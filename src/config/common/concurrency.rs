@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+
+use super::timeframe::Timeframe;
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Concurrency options")]
+pub struct ConcurrencyArgs {
+    /// How many requests may be in flight to upstreams at once, across all routes.
+    ///
+    /// Requests over the limit wait in a queue, and get a `503` if the queue is full
+    /// or they waited longer than `--queue-timeout`.
+    /// Routes can set their own limit in the routes file.
+    ///
+    /// Unlimited if this is not set.
+    #[clap(
+        long,
+        value_name = "REQUESTS",
+        value_parser = parse_max_requests,
+        env = "CORS_PROXY_MAX_CONCURRENT_REQUESTS"
+    )]
+    pub max_concurrent_requests: Option<usize>,
+
+    /// How many requests may wait for a free slot once the concurrency limit is reached.
+    #[clap(
+        long,
+        value_name = "REQUESTS",
+        default_value = "100",
+        env = "CORS_PROXY_MAX_QUEUED_REQUESTS"
+    )]
+    pub max_queued_requests: usize,
+
+    /// How long a request may wait for a free slot before it is rejected.
+    ///
+    /// Eg. `300ms` or `5s`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        default_value = "5s",
+        env = "CORS_PROXY_QUEUE_TIMEOUT"
+    )]
+    pub queue_timeout: Timeframe,
+}
+impl ConcurrencyArgs {
+    pub fn to_config(&self) -> Option<ConcurrencyLimitConfig> {
+        self.limit(self.max_concurrent_requests)
+    }
+
+    /// A limit of `max_requests` that queues like the global one
+    pub fn limit(&self, max_requests: Option<usize>) -> Option<ConcurrencyLimitConfig> {
+        Some(ConcurrencyLimitConfig {
+            max_requests: max_requests.filter(|x| *x > 0)?,
+            max_queued: self.max_queued_requests,
+            queue_timeout: self.queue_timeout.into(),
+        })
+    }
+}
+
+fn parse_max_requests(s: &str) -> Result<usize, String> {
+    let max = s
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid number of requests {s:?}: {e}"))?;
+
+    check_max_requests(max)
+}
+
+/// Checks a concurrency limit fits in a semaphore
+pub fn check_max_requests(max: usize) -> Result<usize, String> {
+    if max > Semaphore::MAX_PERMITS {
+        return Err(format!(
+            "At most {} concurrent requests can be allowed",
+            Semaphore::MAX_PERMITS
+        ));
+    }
+
+    Ok(max)
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimitConfig {
+    pub max_requests: usize,
+
+    pub max_queued: usize,
+
+    pub queue_timeout: Duration,
+}
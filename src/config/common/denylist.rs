@@ -0,0 +1,152 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Networks that aren't on the public internet.
+///
+/// Open proxy destinations are always checked against these.
+pub const DEFAULT_DENYLIST: &[&str] = &[
+    // RFC 1918
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    // Shared address space used by carrier-grade NAT
+    "100.64.0.0/10",
+    // Loopback
+    "127.0.0.0/8",
+    "::1/128",
+    // Unspecified
+    "0.0.0.0/8",
+    "::/128",
+    // IETF protocol assignments
+    "192.0.0.0/24",
+    // Benchmarking
+    "198.18.0.0/15",
+    // Reserved for future use, including the broadcast address
+    "240.0.0.0/4",
+    // Link-local, including the cloud metadata endpoint at 169.254.169.254
+    "169.254.0.0/16",
+    "fe80::/10",
+    // IPv6 unique local
+    "fc00::/7",
+    // NAT64, which embeds IPv4 addresses (including the ones above) in IPv6 ones
+    "64:ff9b::/96",
+    "64:ff9b:1::/48",
+    // 6to4, which embeds IPv4 addresses the same way
+    "2002::/16",
+    // Multicast
+    "224.0.0.0/4",
+    "ff00::/8",
+];
+
+/// Networks that upstreams may not resolve to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denylist(pub Vec<IpNet>);
+impl Denylist {
+    pub fn default_networks() -> Self {
+        Self(
+            DEFAULT_DENYLIST
+                .iter()
+                .filter_map(|x| x.parse().ok())
+                .collect(),
+        )
+    }
+
+    /// Parses a network, a single address, `default` for the default networks or `none`
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+
+        match s.to_lowercase().as_str() {
+            "default" => return Ok(Self::default_networks()),
+            "none" => return Ok(Self(vec![])),
+            _ => {}
+        }
+
+        s.parse::<IpNet>()
+            .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+            .map(|x| Self(vec![x]))
+            .map_err(|_| format!("Invalid network {s:?} (eg. 10.0.0.0/8, default or none)"))
+    }
+
+    pub fn denies(&self, addr: IpAddr) -> bool {
+        // IPv4-mapped IPv6 addresses would otherwise slip past the IPv4 networks
+        let addr = addr.to_canonical();
+
+        self.0.iter().any(|net| net.contains(&addr))
+    }
+}
+impl FromIterator<Self> for Denylist {
+    fn from_iter<T: IntoIterator<Item = Self>>(iter: T) -> Self {
+        Self(iter.into_iter().flat_map(|x| x.0).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_networks_parse() {
+        assert_eq!(Denylist::default_networks().0.len(), DEFAULT_DENYLIST.len());
+    }
+
+    #[test]
+    fn default_networks_deny_non_public_addresses() {
+        let denylist = Denylist::default_networks();
+
+        for addr in [
+            "0.0.0.0",
+            "0.1.2.3",
+            "10.1.2.3",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.0.0.170",
+            "192.168.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::a00:1",
+            "2002:7f00:1::1",
+            "2002:a9fe:a9fe::",
+            "fc00::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "ff02::1",
+        ] {
+            assert!(
+                denylist.denies(addr.parse().expect("valid address")),
+                "{addr}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_networks_allow_public_addresses() {
+        let denylist = Denylist::default_networks();
+
+        for addr in [
+            "1.1.1.1",
+            "192.0.1.1",
+            "198.20.0.1",
+            "203.0.113.1",
+            "::ffff:8.8.8.8",
+            "2003::1",
+            "2606:4700::1111",
+        ] {
+            assert!(
+                !denylist.denies(addr.parse().expect("valid address")),
+                "{addr}"
+            );
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use super::{size::ByteSize, timeframe::Timeframe};
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Client request limits")]
+pub struct DownstreamArgs {
+    /// Requests whose headers, including the request line, are larger than this
+    /// get a `431 Request Header Fields Too Large`.
+    ///
+    /// Headers over 1MiB are always rejected.
+    /// There are no timeouts for reading headers or bodies from clients,
+    /// so clients that send slowly can hold connections open.
+    ///
+    /// Eg. `8KiB` or `64KiB`
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser = ByteSize::parse_str,
+        env = "CORS_PROXY_MAX_REQUEST_HEADER_SIZE"
+    )]
+    pub max_request_header_size: Option<ByteSize>,
+
+    /// Requests with a body larger than this get a `413 Content Too Large`.
+    ///
+    /// Chunked bodies, which have no `Content-Length`, aren't limited.
+    ///
+    /// Eg. `1MiB` or `100MiB`
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser = ByteSize::parse_str,
+        env = "CORS_PROXY_MAX_REQUEST_BODY_SIZE"
+    )]
+    pub max_request_body_size: Option<ByteSize>,
+
+    /// How long an idle client connection is kept open for the next request.
+    ///
+    /// It's rounded up to whole seconds, and `0s` closes the connection after every request.
+    /// HTTP/2 connections aren't affected.
+    ///
+    /// Eg. `5s` or `1m`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        default_value = "60s",
+        env = "CORS_PROXY_DOWNSTREAM_KEEPALIVE_TIMEOUT"
+    )]
+    pub downstream_keepalive_timeout: Timeframe,
+}
+impl DownstreamArgs {
+    pub fn to_config(&self) -> DownstreamConfig {
+        DownstreamConfig {
+            max_header_size: self.max_request_header_size.map(Into::into),
+            max_body_size: self.max_request_body_size.map(Into::into),
+            keepalive_timeout: Some(Duration::from(self.downstream_keepalive_timeout))
+                .filter(|x| !x.is_zero())
+                .map(|x| x.as_secs() + u64::from(x.subsec_nanos() > 0)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DownstreamConfig {
+    pub max_header_size: Option<usize>,
+
+    pub max_body_size: Option<usize>,
+
+    /// In seconds, `None` if connections aren't kept alive
+    pub keepalive_timeout: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Debug, Parser)]
+    struct TestArgs {
+        #[clap(flatten)]
+        downstream: DownstreamArgs,
+    }
+
+    fn keepalive_timeout(args: &[&str]) -> Option<u64> {
+        TestArgs::parse_from([&["cors-proxy"], args].concat())
+            .downstream
+            .to_config()
+            .keepalive_timeout
+    }
+
+    #[test]
+    fn keepalive_timeout_defaults_to_a_minute() {
+        assert_eq!(keepalive_timeout(&[]), Some(60));
+    }
+
+    #[test]
+    fn keepalive_timeout_rounds_up_to_seconds() {
+        assert_eq!(
+            keepalive_timeout(&["--downstream-keepalive-timeout", "1500ms"]),
+            Some(2)
+        );
+        assert_eq!(
+            keepalive_timeout(&["--downstream-keepalive-timeout", "2s"]),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn zero_keepalive_timeout_closes_connections() {
+        assert_eq!(
+            keepalive_timeout(&["--downstream-keepalive-timeout", "0s"]),
+            None
+        );
+    }
+}
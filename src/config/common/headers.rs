@@ -0,0 +1,226 @@
+use http::{HeaderName, HeaderValue};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderAction {
+    /// Add a value, keeping any existing ones
+    Add,
+    /// Replace all existing values
+    Set,
+    Remove,
+}
+
+/// A rule that changes a header of a request or response
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    pub action: HeaderAction,
+    /// The header name with its original casing
+    pub name: String,
+    pub value: Template,
+}
+impl HeaderRule {
+    /// Parses a rule like `set:X-Frame-Options=DENY`, `add:X-Client={client_ip}` or `remove:Server`
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+
+        let (action, rule) = s.split_once(':').ok_or_else(|| {
+            format!("Invalid header rule {s:?} (expected add:NAME=VALUE, set:NAME=VALUE or remove:NAME)")
+        })?;
+
+        let action = match action.trim().to_lowercase().as_str() {
+            "add" => HeaderAction::Add,
+            "set" => HeaderAction::Set,
+            "remove" => HeaderAction::Remove,
+            x => {
+                return Err(format!(
+                    "Invalid header rule action {x:?} (expected add, set or remove)"
+                ))
+            }
+        };
+
+        let (name, value) = match (action, rule.split_once('=')) {
+            (HeaderAction::Remove, None) => (rule, ""),
+            (HeaderAction::Remove, Some(_)) => {
+                return Err(format!("Header rule {s:?} can't have a value"));
+            }
+            (_, Some(x)) => x,
+            (_, None) => return Err(format!("Header rule {s:?} is missing a value")),
+        };
+
+        let name = name.trim();
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| format!("Invalid header name {name:?}: {e}"))?;
+
+        let value = Template::parse_str(value.trim())?;
+        if let Some(literal) = value.as_literal() {
+            HeaderValue::from_str(literal)
+                .map_err(|e| format!("Invalid header value {literal:?}: {e}"))?;
+        }
+
+        Ok(Self {
+            action,
+            name: name.to_string(),
+            value,
+        })
+    }
+}
+
+/// The request details a template can refer to, eg. `{client_ip}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateVar {
+    ClientIp,
+    RequestId,
+    Route,
+    Host,
+    Method,
+    Path,
+}
+impl TemplateVar {
+    fn parse_str(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "client_ip" => Ok(Self::ClientIp),
+            "request_id" => Ok(Self::RequestId),
+            "route" => Ok(Self::Route),
+            "host" => Ok(Self::Host),
+            "method" => Ok(Self::Method),
+            "path" => Ok(Self::Path),
+            x => Err(format!(
+                "Unknown template variable {x:?} (expected client_ip, request_id, route, host, \
+                 method or path)"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Var(TemplateVar),
+}
+
+/// A header value with `{variable}` placeholders
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<TemplatePart>);
+impl Template {
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        let mut parts = vec![];
+        let mut rest = s;
+
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed template variable in {s:?}"))?;
+
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            parts.push(TemplatePart::Var(TemplateVar::parse_str(
+                &rest[start + 1..start + end],
+            )?));
+
+            rest = &rest[start + end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+
+        Ok(Self(parts))
+    }
+
+    /// The value if the template has no variables
+    fn as_literal(&self) -> Option<&str> {
+        match self.0.as_slice() {
+            [] => Some(""),
+            [TemplatePart::Literal(x)] => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn render(&self, var: impl Fn(TemplateVar) -> String) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(x) => x.clone(),
+                TemplatePart::Var(x) => var(*x),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &Template) -> String {
+        template.render(|var| format!("<{var:?}>"))
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule = HeaderRule::parse_str(" set:X-Frame-Options = DENY ").expect("valid rule");
+        assert_eq!(rule.action, HeaderAction::Set);
+        assert_eq!(rule.name, "X-Frame-Options");
+        assert_eq!(rule.value.as_literal(), Some("DENY"));
+
+        let rule = HeaderRule::parse_str("ADD:X-Client={client_ip}").expect("valid rule");
+        assert_eq!(rule.action, HeaderAction::Add);
+        assert_eq!(render(&rule.value), "<ClientIp>");
+
+        let rule = HeaderRule::parse_str("remove:Server").expect("valid rule");
+        assert_eq!(rule.action, HeaderAction::Remove);
+        assert_eq!(rule.name, "Server");
+    }
+
+    #[test]
+    fn values_can_contain_separators() {
+        let rule =
+            HeaderRule::parse_str("set:Link=<https://a.example>; rel=preload").expect("valid rule");
+
+        assert_eq!(
+            rule.value.as_literal(),
+            Some("<https://a.example>; rel=preload")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for rule in [
+            "X-Foo=bar",
+            "replace:X-Foo=bar",
+            "set:X-Foo",
+            "remove:X-Foo=bar",
+            "set:X Foo=bar",
+            "set:=bar",
+            "set:X-Foo=a\nb",
+            "set:X-Foo={nope}",
+        ] {
+            assert!(HeaderRule::parse_str(rule).is_err(), "{rule:?}");
+        }
+    }
+
+    #[test]
+    fn parses_templates() {
+        let template = Template::parse_str("{method} {path}?id={request_id}").expect("valid");
+        assert_eq!(render(&template), "<Method> <Path>?id=<RequestId>");
+        assert_eq!(template.as_literal(), None);
+
+        let template = Template::parse_str("{ host }-{route}").expect("valid");
+        assert_eq!(render(&template), "<Host>-<Route>");
+
+        assert_eq!(
+            Template::parse_str("").expect("valid").as_literal(),
+            Some("")
+        );
+        assert_eq!(
+            Template::parse_str("plain").expect("valid").as_literal(),
+            Some("plain")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(Template::parse_str("{client_ip").is_err());
+        assert!(Template::parse_str("a{}b").is_err());
+        assert!(Template::parse_str("{unknown}").is_err());
+    }
+}
@@ -0,0 +1,19 @@
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Metrics options")]
+pub struct MetricsConfig {
+    /// The port on which the Prometheus metrics endpoint will listen.
+    ///
+    /// The metrics endpoint is disabled if this is not set.
+    #[arg(long, env = "CORS_PROXY_METRICS_PORT", value_parser = clap::value_parser!(u16).range(1..65535))]
+    pub metrics_port: Option<u16>,
+
+    /// The host on which the Prometheus metrics endpoint will listen.
+    #[arg(long, default_value = "0.0.0.0", env = "CORS_PROXY_METRICS_HOST")]
+    pub metrics_host: String,
+}
+impl MetricsConfig {
+    pub fn addr_string(&self) -> Option<String> {
+        self.metrics_port
+            .map(|port| format!("{}:{}", self.metrics_host, port))
+    }
+}
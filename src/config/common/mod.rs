@@ -1,4 +1,22 @@
+pub mod access_log;
+pub mod cache;
+pub mod circuit_breaker;
+pub mod compression;
+pub mod concurrency;
+pub mod denylist;
+pub mod downstream;
+pub mod headers;
+pub mod metrics;
+pub mod open_proxy;
 pub mod pingora;
 pub mod proxy;
+pub mod rate_limit;
+pub mod retry;
+pub mod rewrite;
+pub mod routes;
 pub mod server;
+pub mod size;
+pub mod streaming;
+pub mod telemetry;
 pub mod timeframe;
+pub mod upstream;
@@ -0,0 +1,123 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use super::denylist::Denylist;
+
+/// A destination the open proxy may forward requests to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    /// `*`, any host
+    Any,
+    /// A host name, eg. `api.example.com`
+    Host(String),
+    /// Any subdomain of a host name, eg. `*.example.com`
+    Subdomains(String),
+    /// Any address in a network, eg. `203.0.113.0/24`
+    Network(IpNet),
+}
+impl Destination {
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        let s = s.trim().to_lowercase();
+
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Self::Network(net));
+        }
+
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            return Ok(Self::Network(addr.into()));
+        }
+
+        if let Some(domain) = s.strip_prefix("*.") {
+            return Ok(Self::Subdomains(format!(".{domain}")));
+        }
+
+        if s.is_empty() || s.contains(|c: char| c.is_whitespace() || matches!(c, '/' | ':' | '*')) {
+            return Err(format!(
+                "Invalid destination {s:?} (eg. api.example.com, *.example.com or 203.0.113.0/24)"
+            ));
+        }
+
+        Ok(Self::Host(s))
+    }
+
+    /// Whether a request to `host`, which resolved to `addr`, is allowed by this destination
+    pub fn allows(&self, host: &str, addr: IpAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Host(x) => host.eq_ignore_ascii_case(x),
+            Self::Subdomains(x) => host.to_lowercase().ends_with(x.as_str()),
+            Self::Network(x) => x.contains(&addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Open proxy options")]
+#[allow(clippy::struct_field_names)]
+pub struct OpenProxyArgs {
+    /// Forward requests to the URL given in their path or `url` query parameter.
+    ///
+    /// For example, `/https://api.example.com/users` or `/?url=https%3A%2F%2Fapi.example.com%2Fusers`.
+    /// Requests without a URL are sent to `--proxy-to` as usual.
+    #[clap(long, env = "CORS_PROXY_OPEN_PROXY")]
+    pub open_proxy: bool,
+
+    /// The destinations the open proxy may forward requests to.
+    ///
+    /// Host names, `*.` wildcard subdomains, or networks in CIDR notation.
+    /// `*` allows any destination. Nothing is allowed by default.
+    ///
+    /// For example, `api.example.com`, `*.example.com` or `203.0.113.0/24,api.example.com`
+    #[clap(
+        long,
+        value_name = "DESTINATION",
+        value_delimiter = ',',
+        value_parser = Destination::parse_str,
+        env = "CORS_PROXY_OPEN_PROXY_ALLOWLIST"
+    )]
+    pub open_proxy_allowlist: Vec<Destination>,
+
+    /// Allow the open proxy to forward requests to addresses in the upstream deny-list.
+    ///
+    /// The default deny-list and `--upstream-denylist` are blocked otherwise,
+    /// so the proxy can't be used to reach internal services.
+    #[clap(long, env = "CORS_PROXY_OPEN_PROXY_ALLOW_PRIVATE")]
+    pub open_proxy_allow_private: bool,
+}
+impl OpenProxyArgs {
+    pub fn to_config(&self, upstream_denylist: Option<&Denylist>) -> Option<OpenProxyConfig> {
+        if !self.open_proxy {
+            return None;
+        }
+
+        let denylist = if self.open_proxy_allow_private {
+            Denylist(vec![])
+        } else {
+            std::iter::once(Denylist::default_networks())
+                .chain(upstream_denylist.cloned())
+                .collect()
+        };
+
+        Some(OpenProxyConfig {
+            allowlist: self.open_proxy_allowlist.clone(),
+            denylist,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenProxyConfig {
+    pub allowlist: Vec<Destination>,
+
+    pub denylist: Denylist,
+}
+impl OpenProxyConfig {
+    pub fn allows(&self, host: &str, addr: IpAddr) -> bool {
+        self.allowlist.iter().any(|x| x.allows(host, addr)) && !self.denylist.denies(addr)
+    }
+}
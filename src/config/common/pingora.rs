@@ -23,7 +23,7 @@ pub struct PingoraConfig {
     /// The path to the Pingora server configuration file.
     /// Should be a YAML file.
     ///
-    /// Reference: https://docs.rs/pingora/0.1.0/pingora/server/configuration/struct.ServerConf.html
+    /// Reference: <https://docs.rs/pingora/0.1.0/pingora/server/configuration/struct.ServerConf.html>
     #[clap(short, long, value_name = "FILE_PATH", env = "CORS_PROXY_CONFIG_PATH")]
     pub config_file: Option<PathBuf>,
 
@@ -1,8 +1,46 @@
-use std::{
-    collections::HashSet, convert::Into, net::ToSocketAddrs, string::ToString, time::Duration,
+use std::{collections::HashSet, convert::Into, string::ToString, time::Duration};
+
+use http::HeaderName;
+use ipnet::IpNet;
+
+use super::{
+    cache::{CacheArgs, CacheConfig},
+    circuit_breaker::{CircuitBreakerArgs, CircuitBreakerConfig},
+    compression::{CompressionArgs, CompressionConfig},
+    concurrency::{ConcurrencyArgs, ConcurrencyLimitConfig},
+    denylist::Denylist,
+    downstream::{DownstreamArgs, DownstreamConfig},
+    headers::HeaderRule,
+    open_proxy::{OpenProxyArgs, OpenProxyConfig},
+    rate_limit::{RateLimitArgs, RateLimitConfig},
+    retry::{RetryArgs, RetryConfig},
+    rewrite::RewriteRule,
+    routes::{RouteConfig, RoutesFile},
+    streaming::{StreamingArgs, StreamingConfig},
+    timeframe::Timeframe,
+    upstream::{Upstream, UpstreamHost, UpstreamProtocol},
 };
 
-use super::timeframe::Timeframe;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ForwardedHeaders {
+    /// Send both the `Forwarded` and `X-Forwarded-*` headers
+    All,
+    /// Only send the standard `Forwarded` header (RFC 7239)
+    Forwarded,
+    /// Only send the `X-Forwarded-For`/`-Proto`/`-Host`/`-Port` headers
+    XForwarded,
+    /// Don't send any forwarding headers
+    None,
+}
+impl ForwardedHeaders {
+    pub const fn forwarded(self) -> bool {
+        matches!(self, Self::All | Self::Forwarded)
+    }
+
+    pub const fn x_forwarded(self) -> bool {
+        matches!(self, Self::All | Self::XForwarded)
+    }
+}
 
 #[derive(Debug, Clone, clap::Args)]
 #[clap(next_help_heading = "Proxy options")]
@@ -10,8 +48,71 @@ pub struct ProxyArgs {
     /// The address to proxy requests to.
     ///
     /// For example, `127.0.0.1:80` or `my-computer.local:1234`
-    #[clap(short, long, value_name = "ADDRESS", env="CORS_PROXY_PROXY_TO", value_parser = parse_socket_addr)]
-    pub proxy_to: std::net::SocketAddr,
+    #[clap(short, long, value_name = "ADDRESS", env="CORS_PROXY_PROXY_TO", value_parser = Upstream::parse_str)]
+    pub proxy_to: Upstream,
+
+    /// The `Host` header sent to the upstream.
+    ///
+    /// `preserve` sends the `Host` of the client request,
+    /// `upstream` sends the host name of `--proxy-to`, and anything else is sent as is.
+    /// TLS connections to the upstream use it as the server name.
+    ///
+    /// For example, `preserve`, `upstream` or `api.internal.example.com`
+    #[clap(
+        long,
+        value_name = "HOST",
+        default_value = "preserve",
+        value_parser = UpstreamHost::parse_str,
+        env = "CORS_PROXY_UPSTREAM_HOST"
+    )]
+    pub upstream_host: UpstreamHost,
+
+    /// The HTTP version used to talk to the upstream.
+    ///
+    /// `http1` only uses HTTP/1.1.
+    /// `h2` uses HTTP/2 if the upstream offers it when connecting over TLS, and HTTP/1.1 otherwise.
+    /// `h2c` always uses HTTP/2 without TLS, for upstreams known to support it.
+    #[clap(
+        long,
+        value_name = "PROTOCOL",
+        default_value = "http1",
+        value_parser = UpstreamProtocol::parse_str,
+        env = "CORS_PROXY_UPSTREAM_PROTOCOL"
+    )]
+    pub upstream_protocol: UpstreamProtocol,
+
+    /// How many requests may share one HTTP/2 connection to the upstream at once.
+    #[clap(
+        long,
+        value_name = "STREAMS",
+        default_value = "100",
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "CORS_PROXY_UPSTREAM_H2_MAX_STREAMS"
+    )]
+    pub upstream_h2_max_streams: u32,
+
+    /// Translate gRPC-Web requests from browsers into gRPC requests to the upstream.
+    ///
+    /// Requests with an `application/grpc-web` or `application/grpc-web-text` content type
+    /// are sent over HTTP/2, using h2c unless `--upstream-protocol` says otherwise.
+    /// The base64 bodies of `application/grpc-web-text` requests and their responses
+    /// are decoded and encoded.
+    #[clap(long, env = "CORS_PROXY_GRPC_WEB")]
+    pub grpc_web: bool,
+
+    /// How often upstreams given by host name are resolved again,
+    /// so the proxy follows their addresses when they change.
+    ///
+    /// Eg. `10s` or `5min`. `0s` turns re-resolution off.
+    ///
+    /// Defaults to `30s`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        default_value = "30s",
+        env = "CORS_PROXY_UPSTREAM_RESOLVE_INTERVAL"
+    )]
+    pub upstream_resolve_interval: Timeframe,
 
     /// Set which host names are allowed to be proxied.
     ///
@@ -51,10 +152,10 @@ pub struct ProxyArgs {
     )]
     pub use_tls: Option<bool>,
 
-    /// How long connect() call should be wait
+    /// How long `connect()` call should be wait
     /// before it returns a timeout error.
     ///
-    /// Eg. `300ms` or `5s``
+    /// Eg. `300ms` or `5s`
     ///
     /// Defaults to `5s`
     #[clap(
@@ -89,6 +190,220 @@ pub struct ProxyArgs {
         env = "CORS_PROXY_IDLE_TIMEOUT"
     )]
     pub idle_timeout: Option<Timeframe>,
+
+    /// How long a single read from the upstream may take, eg. while waiting for its response.
+    ///
+    /// Requests that time out get a `504 Gateway Timeout`. There's no timeout by default.
+    ///
+    /// Eg. `300ms` or `5s`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        env = "CORS_PROXY_READ_TIMEOUT"
+    )]
+    pub read_timeout: Option<Timeframe>,
+
+    /// How long a single write to the upstream may take, eg. while sending the request body.
+    ///
+    /// Requests that time out get a `504 Gateway Timeout`. There's no timeout by default.
+    ///
+    /// Eg. `300ms` or `5s`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        env = "CORS_PROXY_WRITE_TIMEOUT"
+    )]
+    pub write_timeout: Option<Timeframe>,
+
+    /// How long the upstream may take to respond, from when the request came in,
+    /// including connecting and any retries.
+    ///
+    /// Requests that time out get a `504 Gateway Timeout`. There's no timeout by default.
+    ///
+    /// Eg. `10s` or `1m`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        env = "CORS_PROXY_REQUEST_TIMEOUT"
+    )]
+    pub request_timeout: Option<Timeframe>,
+
+    /// The header used to pass the request ID along.
+    ///
+    /// A valid ID in this header of an incoming request is reused, otherwise a new one is generated.
+    /// The ID is forwarded to the upstream and returned in the response.
+    ///
+    /// For example, `X-Request-Id`
+    #[clap(
+        long,
+        value_name = "HEADER",
+        default_value = "X-CorsProxy-Request-Id",
+        value_parser = parse_header_name,
+        env = "CORS_PROXY_REQUEST_ID_HEADER"
+    )]
+    pub request_id_header: String,
+
+    /// Which forwarding headers to send to the upstream.
+    ///
+    /// None are sent to open proxy destinations.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "all",
+        env = "CORS_PROXY_FORWARDED_HEADERS"
+    )]
+    pub forwarded_headers: ForwardedHeaders,
+
+    /// The networks of proxies in front of this one that are trusted
+    /// to report the client address in forwarding headers.
+    ///
+    /// Forwarding headers on incoming requests are only kept (and extended)
+    /// if the connection comes from one of these networks, otherwise they are replaced
+    /// so clients can't spoof them.
+    /// The client address is the address of the connection, unless it's a trusted proxy.
+    /// Then it's the nearest address in the `Forwarded` or `X-Forwarded-For` chain
+    /// that is not in one of these networks.
+    ///
+    /// For example, `10.0.0.0/8`, `192.168.1.1` or `10.0.0.0/8,fd00::/8`
+    #[clap(
+        long,
+        value_name = "CIDR",
+        value_delimiter = ',',
+        value_parser = parse_ip_net,
+        env = "CORS_PROXY_TRUSTED_PROXIES"
+    )]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Networks that upstreams may not resolve to.
+    ///
+    /// Upstreams given by host name are checked after DNS resolution, against every address
+    /// they resolve to, so names pointing at internal addresses are caught.
+    /// `default` stands for the private, loopback, link-local, shared, reserved, benchmarking,
+    /// multicast, NAT64, 6to4 and IPv6 unique local networks,
+    /// which include the cloud metadata address `169.254.169.254`, and `none` for no networks.
+    ///
+    /// Open proxy destinations are always checked against `default` as well as these networks.
+    /// Other upstreams are only checked if this is set.
+    ///
+    /// For example, `default`, `default,100.64.0.0/10` or `none`
+    #[clap(
+        long,
+        value_name = "CIDR",
+        value_delimiter = ',',
+        value_parser = Denylist::parse_str,
+        env = "CORS_PROXY_UPSTREAM_DENYLIST"
+    )]
+    pub upstream_denylist: Vec<Denylist>,
+
+    /// Only allow requests from clients in these networks.
+    ///
+    /// The client address is the address of the connection, or the one reported by `--trusted-proxies`.
+    /// Requests with an unknown client address are rejected.
+    /// By default, all clients are allowed.
+    ///
+    /// For example, `203.0.113.0/24` or `192.0.2.1,2001:db8::/32`
+    #[clap(
+        long,
+        value_name = "CIDR",
+        value_delimiter = ',',
+        value_parser = parse_ip_net,
+        env = "CORS_PROXY_CLIENT_ALLOWLIST"
+    )]
+    pub client_allowlist: Vec<IpNet>,
+
+    /// Reject requests from clients in these networks.
+    ///
+    /// Takes precedence over `--client-allowlist`.
+    /// Requests with an unknown client address are not rejected by this list.
+    ///
+    /// For example, `198.51.100.0/24` or `192.0.2.1,2001:db8::/32`
+    #[clap(
+        long,
+        value_name = "CIDR",
+        value_delimiter = ',',
+        value_parser = parse_ip_net,
+        env = "CORS_PROXY_CLIENT_DENYLIST"
+    )]
+    pub client_denylist: Vec<IpNet>,
+
+    /// Change a header of requests sent to the upstream.
+    ///
+    /// One of `add:NAME=VALUE`, `set:NAME=VALUE` or `remove:NAME`, and can be given multiple times.
+    /// Values can refer to the request with `{client_ip}`, `{request_id}`, `{route}`,
+    /// `{host}`, `{method}` and `{path}`.
+    ///
+    /// For example, `set:X-Client-Ip={client_ip}` or `remove:Cookie`
+    #[clap(
+        long = "request-header",
+        value_name = "RULE",
+        value_parser = HeaderRule::parse_str,
+        env = "CORS_PROXY_REQUEST_HEADER"
+    )]
+    pub request_headers: Vec<HeaderRule>,
+
+    /// Change a header of responses sent to the client.
+    ///
+    /// Takes the same rules as `--request-header`.
+    ///
+    /// For example, `remove:X-Powered-By` or `set:X-Frame-Options=DENY`
+    #[clap(
+        long = "response-header",
+        value_name = "RULE",
+        value_parser = HeaderRule::parse_str,
+        env = "CORS_PROXY_RESPONSE_HEADER"
+    )]
+    pub response_headers: Vec<HeaderRule>,
+
+    /// Rewrite the path and query of requests sent to the upstream.
+    ///
+    /// A regular expression and its replacement, separated by a space, and can be given
+    /// multiple times. The first match in the path and query is replaced, and the replacement
+    /// can refer to capture groups with `$1` or `${name}`.
+    /// Only the first matching rule is applied, and the rules of routes are tried first.
+    ///
+    /// For example, `^/api/(.*)$ /v1/$1`
+    #[clap(
+        long = "rewrite",
+        value_name = "RULE",
+        value_parser = RewriteRule::parse_str,
+        env = "CORS_PROXY_REWRITE"
+    )]
+    pub rewrites: Vec<RewriteRule>,
+
+    /// A YAML file with a list of routes.
+    ///
+    /// Requests are handled by the first route that matches them,
+    /// or by the options given here if none do.
+    /// See the README for the format.
+    #[clap(long, value_name = "FILE", value_parser = RoutesFile::parse_path, env = "CORS_PROXY_ROUTES")]
+    pub routes: Option<RoutesFile>,
+
+    #[clap(flatten)]
+    pub rate_limit: RateLimitArgs,
+
+    #[clap(flatten)]
+    pub concurrency: ConcurrencyArgs,
+
+    #[clap(flatten)]
+    pub open_proxy: OpenProxyArgs,
+
+    #[clap(flatten)]
+    pub cache: CacheArgs,
+
+    #[clap(flatten)]
+    pub compression: CompressionArgs,
+
+    #[clap(flatten)]
+    pub retry: RetryArgs,
+
+    #[clap(flatten)]
+    pub circuit_breaker: CircuitBreakerArgs,
+
+    #[clap(flatten)]
+    pub downstream: DownstreamArgs,
+
+    #[clap(flatten)]
+    pub streaming: StreamingArgs,
 }
 impl ProxyArgs {
     pub fn to_config(&self) -> ProxyConfig {
@@ -98,7 +413,17 @@ impl ProxyArgs {
 
 #[derive(Debug, Clone)]
 pub struct ProxyConfig {
-    pub proxy_to: std::net::SocketAddr,
+    pub proxy_to: Upstream,
+
+    pub upstream_host: UpstreamHost,
+
+    pub upstream_protocol: UpstreamProtocol,
+
+    pub upstream_h2_max_streams: usize,
+
+    pub grpc_web: bool,
+
+    pub upstream_resolve_interval: Option<Duration>,
 
     pub host_allowlist: HashSet<String>,
 
@@ -111,20 +436,136 @@ pub struct ProxyConfig {
     pub total_connection_timeout: Duration,
 
     pub idle_timeout: Option<Duration>,
+
+    pub upstream_timeouts: UpstreamTimeouts,
+
+    pub request_id_header: String,
+
+    pub forwarded_headers: ForwardedHeaders,
+
+    pub trusted_proxies: Vec<IpNet>,
+
+    pub client_allowlist: Vec<IpNet>,
+
+    pub client_denylist: Vec<IpNet>,
+
+    pub upstream_denylist: Option<Denylist>,
+
+    pub request_headers: Vec<HeaderRule>,
+
+    pub response_headers: Vec<HeaderRule>,
+
+    pub rewrites: Vec<RewriteRule>,
+
+    pub routes: Vec<RouteConfig>,
+
+    pub rate_limit: Option<RateLimitConfig>,
+
+    pub concurrency: Option<ConcurrencyLimitConfig>,
+
+    pub open_proxy: Option<OpenProxyConfig>,
+
+    pub cache: Option<CacheConfig>,
+
+    pub compression: Option<CompressionConfig>,
+
+    pub decompression: bool,
+
+    pub retry: Option<RetryConfig>,
+
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    pub downstream: DownstreamConfig,
+
+    pub streaming: StreamingConfig,
 }
 impl ProxyConfig {
     fn from_args(args: &ProxyArgs) -> Self {
+        let upstream_denylist = (!args.upstream_denylist.is_empty())
+            .then(|| args.upstream_denylist.iter().cloned().collect::<Denylist>());
+
+        let upstream_timeouts = UpstreamTimeouts {
+            read: args.read_timeout.map(Into::into),
+            write: args.write_timeout.map(Into::into),
+            request: args.request_timeout.map(Into::into),
+        };
+
         Self {
-            proxy_to: args.proxy_to,
+            proxy_to: args.proxy_to.clone(),
+            upstream_host: args.upstream_host.clone(),
+            upstream_protocol: args.upstream_protocol,
+            upstream_h2_max_streams: args.upstream_h2_max_streams as usize,
+            grpc_web: args.grpc_web,
+            upstream_resolve_interval: Some(args.upstream_resolve_interval.into())
+                .filter(|x: &Duration| !x.is_zero()),
             host_allowlist: Self::parse_comma_list(&args.host_allowlist),
             origin_allowlist: Self::parse_comma_list(&args.origin_allowlist),
             use_tls: args.use_tls,
             connection_timeout: args.connection_timeout.into(),
             total_connection_timeout: args.total_connection_timeout.into(),
             idle_timeout: args.idle_timeout.map(Into::into),
+            upstream_timeouts,
+            request_id_header: args.request_id_header.clone(),
+            forwarded_headers: args.forwarded_headers,
+            trusted_proxies: args.trusted_proxies.clone(),
+            client_allowlist: args.client_allowlist.clone(),
+            client_denylist: args.client_denylist.clone(),
+            open_proxy: args.open_proxy.to_config(upstream_denylist.as_ref()),
+            upstream_denylist,
+            request_headers: args.request_headers.clone(),
+            response_headers: args.response_headers.clone(),
+            rewrites: args.rewrites.clone(),
+            routes: args
+                .routes
+                .iter()
+                .flat_map(|x| &x.0)
+                .map(|x| RouteConfig::from_args(x, &args.concurrency, upstream_timeouts))
+                .collect(),
+            rate_limit: args.rate_limit.to_config(),
+            concurrency: args.concurrency.to_config(),
+            cache: args.cache.to_config(),
+            compression: args.compression.to_config(),
+            decompression: args.compression.decompression,
+            retry: args.retry.to_config(),
+            circuit_breaker: args.circuit_breaker.to_config(),
+            downstream: args.downstream.to_config(),
+            streaming: args.streaming.to_config(),
         }
     }
 
+    /// Check the addresses of the upstreams against the deny-list
+    pub fn check_upstreams(&self) -> Result<(), String> {
+        let Some(denylist) = &self.upstream_denylist else {
+            return Ok(());
+        };
+
+        let upstreams = std::iter::once(&self.proxy_to)
+            .chain(self.routes.iter().filter_map(|x| x.proxy_to.as_ref()));
+
+        for upstream in upstreams {
+            let Some(addr) = upstream.addrs.iter().find(|x| denylist.denies(x.ip())) else {
+                continue;
+            };
+
+            return Err(if upstream.is_ip() {
+                format!("Upstream {} is in the upstream deny-list", upstream.host)
+            } else {
+                format!(
+                    "Upstream {:?} resolved to {}, which is in the upstream deny-list",
+                    upstream.host,
+                    addr.ip()
+                )
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The index of the first route that matches the request
+    pub fn match_route(&self, host: Option<&str>, path: &str) -> Option<usize> {
+        self.routes.iter().position(|x| x.matches(host, path))
+    }
+
     fn parse_comma_list(s: &[String]) -> HashSet<String> {
         s.iter()
             .flat_map(|x| {
@@ -137,13 +578,113 @@ impl ProxyConfig {
     }
 }
 
-fn parse_socket_addr(s: &str) -> Result<std::net::SocketAddr, String> {
-    if !s.contains(':') {
-        return Err("Address must contain a port (eg. 127.0.0.1:80)".to_string());
+/// Validates the header name while keeping the original casing
+fn parse_header_name(s: &str) -> Result<String, String> {
+    let s = s.trim();
+
+    HeaderName::from_bytes(s.as_bytes()).map_err(|e| format!("{e}"))?;
+
+    Ok(s.to_string())
+}
+
+/// Parses a network in CIDR notation, or a single address
+pub(super) fn parse_ip_net(s: &str) -> Result<IpNet, String> {
+    let s = s.trim();
+
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<std::net::IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("Invalid network {s:?} (eg. 10.0.0.0/8 or 192.168.1.1)"))
+}
+
+/// Timeouts for requests to the upstream, which routes can override
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UpstreamTimeouts {
+    /// For each read from the upstream
+    pub read: Option<Duration>,
+
+    /// For each write to the upstream
+    pub write: Option<Duration>,
+
+    /// For the upstream to respond, from when the request came in
+    pub request: Option<Duration>,
+}
+impl UpstreamTimeouts {
+    /// These timeouts, with the unset ones taken from `defaults`
+    #[must_use]
+    pub fn or(self, defaults: Self) -> Self {
+        Self {
+            read: self.read.or(defaults.read),
+            write: self.write.or(defaults.write),
+            request: self.request.or(defaults.request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Debug, Parser)]
+    struct TestArgs {
+        #[clap(flatten)]
+        proxy: ProxyArgs,
     }
 
-    s.to_socket_addrs()
-        .map_err(|e| format!("{e:?}"))?
-        .next()
-        .ok_or_else(|| "Failed to parse address".to_string())
+    fn config(args: &[&str]) -> ProxyConfig {
+        TestArgs::try_parse_from(std::iter::once("cors-proxy").chain(args.iter().copied()))
+            .expect("valid arguments")
+            .proxy
+            .to_config()
+    }
+
+    /// An upstream given by host name that resolved to `addrs`
+    fn named(addrs: &[&str]) -> Upstream {
+        Upstream {
+            host: "api.example.com".to_string(),
+            port: 443,
+            addrs: addrs
+                .iter()
+                .map(|x| x.parse().expect("valid address"))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn upstreams_are_only_checked_with_a_denylist() {
+        let config = config(&["--proxy-to", "127.0.0.1:80"]);
+
+        assert!(config.check_upstreams().is_ok());
+    }
+
+    #[test]
+    fn ip_upstreams_are_checked() {
+        let config = config(&[
+            "--proxy-to",
+            "127.0.0.1:80",
+            "--upstream-denylist",
+            "default",
+        ]);
+
+        assert!(config.check_upstreams().is_err());
+    }
+
+    #[test]
+    fn every_resolved_address_is_checked() {
+        let mut config = config(&[
+            "--proxy-to",
+            "203.0.113.1:443",
+            "--upstream-denylist",
+            "default",
+        ]);
+        assert!(config.check_upstreams().is_ok());
+
+        config.proxy_to = named(&["203.0.113.1:443", "203.0.113.2:443"]);
+        assert!(config.check_upstreams().is_ok());
+
+        config.proxy_to = named(&["203.0.113.1:443", "10.0.0.1:443"]);
+        let error = config.check_upstreams().expect_err("upstream is denied");
+        assert!(error.contains("10.0.0.1"), "{error}");
+    }
 }
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use http::HeaderName;
+
+use super::timeframe::Timeframe;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitKey {
+    ClientIp,
+    Origin,
+    Host,
+    Header(HeaderName),
+}
+impl RateLimitKey {
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+
+        match s.to_lowercase().as_str() {
+            "client-ip" | "ip" => Ok(Self::ClientIp),
+            "origin" => Ok(Self::Origin),
+            "host" => Ok(Self::Host),
+            _ => {
+                let header = s.strip_prefix("header:").ok_or_else(|| {
+                    format!(
+                        "Invalid rate limit key {s:?} (expected client-ip, origin, host or \
+                         header:<name>)"
+                    )
+                })?;
+
+                HeaderName::from_bytes(header.trim().as_bytes())
+                    .map(Self::Header)
+                    .map_err(|e| format!("Invalid header name {header:?}: {e}"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Rate limit options")]
+#[allow(clippy::struct_field_names)]
+pub struct RateLimitArgs {
+    /// How many requests each client may make per rate limit period.
+    ///
+    /// Rate limiting is disabled if this is not set.
+    #[clap(long, value_name = "REQUESTS", env = "CORS_PROXY_RATE_LIMIT")]
+    pub rate_limit: Option<u32>,
+
+    /// The period over which `--rate-limit` requests are allowed.
+    ///
+    /// Eg. `1s` or `1min`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        default_value = "1s",
+        env = "CORS_PROXY_RATE_LIMIT_PERIOD"
+    )]
+    pub rate_limit_period: Timeframe,
+
+    /// How many requests a client may make at once before being limited.
+    ///
+    /// Defaults to `--rate-limit`
+    #[clap(long, value_name = "REQUESTS", env = "CORS_PROXY_RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+
+    /// What identifies a client for rate limiting.
+    ///
+    /// One of `client-ip`, `origin`, `host` or `header:<name>` (eg. `header:X-Api-Key`).
+    /// Requests without the key are not rate limited.
+    /// The client IP is the address of the connection, or the one reported by `--trusted-proxies`.
+    #[clap(
+        long,
+        value_name = "KEY",
+        default_value = "client-ip",
+        value_parser = RateLimitKey::parse_str,
+        env = "CORS_PROXY_RATE_LIMIT_KEY"
+    )]
+    pub rate_limit_key: RateLimitKey,
+}
+impl RateLimitArgs {
+    pub fn to_config(&self) -> Option<RateLimitConfig> {
+        let requests = self.rate_limit.filter(|x| *x > 0)?;
+
+        Some(RateLimitConfig {
+            requests,
+            period: self.rate_limit_period.into(),
+            burst: self.rate_limit_burst.unwrap_or(requests).max(1),
+            key: self.rate_limit_key.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub requests: u32,
+
+    pub period: Duration,
+
+    pub burst: u32,
+
+    pub key: RateLimitKey,
+}
@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use super::timeframe::Timeframe;
+
+/// A kind of upstream failure that can be retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryCondition {
+    /// Connecting to the upstream failed
+    Connect,
+    /// The connection failed after it was established, eg. it was reset or timed out
+    Reset,
+    /// The upstream responded with this status code
+    Status(u16),
+}
+impl RetryCondition {
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "connect" => Ok(Self::Connect),
+            "reset" => Ok(Self::Reset),
+            x => x
+                .parse::<u16>()
+                .ok()
+                .filter(|x| (500..600).contains(x))
+                .map(Self::Status)
+                .ok_or_else(|| {
+                    format!("Invalid retry condition {x:?} (expected connect, reset or a 5xx status)")
+                }),
+        }
+    }
+
+    /// The label used for the condition in metrics
+    pub fn as_label(self) -> String {
+        match self {
+            Self::Connect => "connect".to_string(),
+            Self::Reset => "reset".to_string(),
+            Self::Status(x) => x.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Retry options")]
+#[allow(clippy::struct_field_names)]
+pub struct RetryArgs {
+    /// How many times a failed request may be retried.
+    ///
+    /// Requests aren't retried by default.
+    #[clap(long, value_name = "RETRIES", default_value = "0", env = "CORS_PROXY_RETRIES")]
+    pub retries: usize,
+
+    /// Which failures are retried: `connect` for failed connections,
+    /// `reset` for connections that failed after they were established, or a `5xx` status code.
+    #[clap(
+        long,
+        value_name = "CONDITION",
+        value_delimiter = ',',
+        default_value = "connect,reset,502,503,504",
+        value_parser = RetryCondition::parse_str,
+        env = "CORS_PROXY_RETRY_ON"
+    )]
+    pub retry_on: Vec<RetryCondition>,
+
+    /// How long to wait before the first retry.
+    ///
+    /// The wait doubles with each retry, and a random part of it is skipped
+    /// so that clients don't retry in lockstep.
+    ///
+    /// Eg. `50ms` or `1s`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        default_value = "100ms",
+        env = "CORS_PROXY_RETRY_BACKOFF"
+    )]
+    pub retry_backoff: Timeframe,
+
+    /// The longest to wait before a retry.
+    ///
+    /// Eg. `500ms` or `5s`
+    #[clap(
+        long,
+        value_parser = Timeframe::parse_str,
+        default_value = "2s",
+        env = "CORS_PROXY_RETRY_MAX_BACKOFF"
+    )]
+    pub retry_max_backoff: Timeframe,
+
+    /// Also retry requests with methods that aren't idempotent, like `POST` and `PATCH`.
+    #[clap(long, env = "CORS_PROXY_RETRY_NON_IDEMPOTENT")]
+    pub retry_non_idempotent: bool,
+
+    /// The most retries there may be as a fraction of requests, eg. `0.2` for one retry per five requests.
+    ///
+    /// Unused retries are saved up for bursts of failures, but never more than 10,
+    /// which is also what there is at startup.
+    /// Keeps retries from piling more load onto an upstream that is already failing.
+    #[clap(
+        long,
+        value_name = "RATIO",
+        default_value = "0.2",
+        env = "CORS_PROXY_RETRY_BUDGET"
+    )]
+    pub retry_budget: f64,
+}
+impl RetryArgs {
+    pub fn to_config(&self) -> Option<RetryConfig> {
+        if self.retries == 0 {
+            return None;
+        }
+
+        Some(RetryConfig {
+            max_retries: self.retries,
+            conditions: self.retry_on.clone(),
+            backoff: self.retry_backoff.into(),
+            max_backoff: self.retry_max_backoff.into(),
+            non_idempotent: self.retry_non_idempotent,
+            budget: self.retry_budget.max(0.0),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    pub max_retries: usize,
+
+    pub conditions: Vec<RetryCondition>,
+
+    pub backoff: Duration,
+
+    pub max_backoff: Duration,
+
+    pub non_idempotent: bool,
+
+    /// Retries allowed per request
+    pub budget: f64,
+}
@@ -0,0 +1,88 @@
+use regex::Regex;
+
+/// A rule that rewrites the path and query of requests sent to the upstream
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pub pattern: Regex,
+    /// Can refer to capture groups with `$1`, `${1}` or `$name`
+    pub replacement: String,
+}
+impl RewriteRule {
+    /// Parses a rule like `^/api/(.*)$ /v1/$1`
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+
+        let (pattern, replacement) = s
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("Invalid rewrite rule {s:?} (expected REGEX REPLACEMENT)"))?;
+
+        let pattern =
+            Regex::new(pattern).map_err(|e| format!("Invalid rewrite pattern {pattern:?}: {e}"))?;
+
+        Ok(Self {
+            pattern,
+            replacement: replacement.trim().to_string(),
+        })
+    }
+
+    /// The path and query with the first match replaced, or `None` if the rule doesn't match
+    pub fn apply(&self, path_and_query: &str) -> Option<String> {
+        if !self.pattern.is_match(path_and_query) {
+            return None;
+        }
+
+        Some(
+            self.pattern
+                .replacen(path_and_query, 1, &self.replacement)
+                .into_owned(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(rule: &str, path_and_query: &str) -> Option<String> {
+        RewriteRule::parse_str(rule)
+            .expect("valid rule")
+            .apply(path_and_query)
+    }
+
+    #[test]
+    fn anchored_rules_replace_everything() {
+        assert_eq!(
+            apply("^/api/(.*)$ /v1/$1", "/api/users/1").as_deref(),
+            Some("/v1/users/1")
+        );
+        assert_eq!(
+            apply(r"^/u/(?<id>\d+)$ /users?id=${id}", "/u/42").as_deref(),
+            Some("/users?id=42")
+        );
+        assert_eq!(apply("^/api/(.*)$ /v1/$1", "/other/api/x"), None);
+    }
+
+    #[test]
+    fn unanchored_rules_keep_the_rest() {
+        assert_eq!(
+            apply("^/api/ /v1/", "/api/users?page=2").as_deref(),
+            Some("/v1/users?page=2")
+        );
+        assert_eq!(
+            apply("/old/ /new/", "/a/old/b/old/c").as_deref(),
+            Some("/a/new/b/old/c")
+        );
+    }
+
+    #[test]
+    fn rules_can_rewrite_the_query() {
+        assert_eq!(
+            apply("([?&])key=[^&]* ${1}key=redacted", "/a?x=1&key=secret&y=2").as_deref(),
+            Some("/a?x=1&key=redacted&y=2")
+        );
+        assert_eq!(
+            apply(r"^/search\?q= /find?query=", "/search?q=cats").as_deref(),
+            Some("/find?query=cats")
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use std::{collections::HashSet, path::Path, time::Duration};
+
+use serde::{Deserialize, Deserializer};
+
+use super::{
+    concurrency::{check_max_requests, ConcurrencyArgs, ConcurrencyLimitConfig},
+    headers::HeaderRule,
+    proxy::UpstreamTimeouts,
+    rewrite::RewriteRule,
+    timeframe::Timeframe,
+    upstream::{Upstream, UpstreamHost, UpstreamProtocol},
+};
+
+/// The name of the route requests fall back to when no configured route matches
+pub const DEFAULT_ROUTE: &str = "default";
+
+/// The routes loaded from a routes file
+#[derive(Debug, Clone)]
+pub struct RoutesFile(pub Vec<RouteArgs>);
+impl RoutesFile {
+    pub fn parse_path(path: &str) -> Result<Self, String> {
+        let path = Path::new(path);
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read routes file {}: {e}", path.display()))?;
+
+        let routes: Vec<RouteArgs> = serde_yaml::from_str(&contents)
+            .map_err(|e| format!("Failed to parse routes file {}: {e}", path.display()))?;
+
+        let mut names = HashSet::new();
+        for route in &routes {
+            if route.name == DEFAULT_ROUTE || !names.insert(route.name.as_str()) {
+                return Err(format!("Duplicate route name {:?}", route.name));
+            }
+
+            if let Some(max) = route.max_concurrent_requests {
+                check_max_requests(max)
+                    .map_err(|e| format!("Invalid route {:?}: {e}", route.name))?;
+            }
+        }
+
+        Ok(Self(routes))
+    }
+}
+
+/// A route as written in the routes file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteArgs {
+    /// Used in logs and as the `route` metric label
+    pub name: String,
+
+    /// Only match requests with this `Host` header
+    #[serde(default)]
+    pub host: Option<String>,
+
+    /// Only match requests whose path starts with these path segments
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+
+    /// Send matching requests here instead of `--proxy-to`
+    #[serde(default, deserialize_with = "deserialize_upstream")]
+    pub proxy_to: Option<Upstream>,
+
+    /// Overrides `--upstream-host`
+    #[serde(default, deserialize_with = "deserialize_upstream_host")]
+    pub upstream_host: Option<UpstreamHost>,
+
+    /// Overrides `--upstream-protocol`
+    #[serde(default, deserialize_with = "deserialize_upstream_protocol")]
+    pub upstream_protocol: Option<UpstreamProtocol>,
+
+    /// Overrides `--grpc-web`
+    #[serde(default)]
+    pub grpc_web: Option<bool>,
+
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+
+    #[serde(default)]
+    pub max_queued_requests: Option<usize>,
+
+    #[serde(default, deserialize_with = "deserialize_timeframe")]
+    pub queue_timeout: Option<Duration>,
+
+    /// Overrides `--read-timeout`
+    #[serde(default, deserialize_with = "deserialize_timeframe")]
+    pub read_timeout: Option<Duration>,
+
+    /// Overrides `--write-timeout`
+    #[serde(default, deserialize_with = "deserialize_timeframe")]
+    pub write_timeout: Option<Duration>,
+
+    /// Overrides `--request-timeout`
+    #[serde(default, deserialize_with = "deserialize_timeframe")]
+    pub request_timeout: Option<Duration>,
+
+    /// Applied after the `--request-header` rules
+    #[serde(default, deserialize_with = "deserialize_header_rules")]
+    pub request_headers: Vec<HeaderRule>,
+
+    /// Applied after the `--response-header` rules
+    #[serde(default, deserialize_with = "deserialize_header_rules")]
+    pub response_headers: Vec<HeaderRule>,
+
+    /// Tried before the `--rewrite` rules
+    #[serde(default, deserialize_with = "deserialize_rewrite_rules")]
+    pub rewrites: Vec<RewriteRule>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteConfig {
+    pub name: String,
+
+    pub host: Option<String>,
+
+    pub path_prefix: Option<String>,
+
+    pub proxy_to: Option<Upstream>,
+
+    pub upstream_host: Option<UpstreamHost>,
+
+    pub upstream_protocol: Option<UpstreamProtocol>,
+
+    pub grpc_web: Option<bool>,
+
+    pub concurrency: Option<ConcurrencyLimitConfig>,
+
+    pub upstream_timeouts: UpstreamTimeouts,
+
+    pub request_headers: Vec<HeaderRule>,
+
+    pub response_headers: Vec<HeaderRule>,
+
+    pub rewrites: Vec<RewriteRule>,
+}
+impl RouteConfig {
+    pub fn from_args(
+        args: &RouteArgs,
+        concurrency: &ConcurrencyArgs,
+        upstream_timeouts: UpstreamTimeouts,
+    ) -> Self {
+        let concurrency = concurrency
+            .limit(args.max_concurrent_requests)
+            .map(|limit| ConcurrencyLimitConfig {
+                max_queued: args.max_queued_requests.unwrap_or(limit.max_queued),
+                queue_timeout: args.queue_timeout.unwrap_or(limit.queue_timeout),
+                ..limit
+            });
+
+        Self {
+            name: args.name.clone(),
+            host: args.host.as_deref().map(str::to_lowercase),
+            path_prefix: args.path_prefix.clone(),
+            proxy_to: args.proxy_to.clone(),
+            upstream_host: args.upstream_host.clone(),
+            upstream_protocol: args.upstream_protocol,
+            grpc_web: args.grpc_web,
+            concurrency,
+            upstream_timeouts: UpstreamTimeouts {
+                read: args.read_timeout,
+                write: args.write_timeout,
+                request: args.request_timeout,
+            }
+            .or(upstream_timeouts),
+            request_headers: args.request_headers.clone(),
+            response_headers: args.response_headers.clone(),
+            rewrites: args.rewrites.clone(),
+        }
+    }
+
+    pub fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = self
+            .host
+            .as_ref()
+            .is_none_or(|x| host.is_some_and(|host| host_matches(x, host)));
+
+        let path_matches = self
+            .path_prefix
+            .as_ref()
+            .is_none_or(|x| path_matches(x, path));
+
+        host_matches && path_matches
+    }
+}
+
+/// Whether a `Host` header is the route's host, with or without a port
+fn host_matches(route_host: &str, host: &str) -> bool {
+    host.eq_ignore_ascii_case(route_host) || strip_port(host).eq_ignore_ascii_case(route_host)
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|x| x.is_ascii_digit()) && !name.is_empty() => name,
+        _ => host,
+    }
+}
+
+/// Whether the path is under the prefix, matching whole path segments so `/v1` doesn't match `/v10`
+fn path_matches(prefix: &str, path: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'))
+}
+
+fn deserialize_upstream<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Upstream>, D::Error> {
+    Option::<String>::deserialize(d)?
+        .map(|x| Upstream::parse_str(&x).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_upstream_host<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<UpstreamHost>, D::Error> {
+    Option::<String>::deserialize(d)?
+        .map(|x| UpstreamHost::parse_str(&x).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_upstream_protocol<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<UpstreamProtocol>, D::Error> {
+    Option::<String>::deserialize(d)?
+        .map(|x| UpstreamProtocol::parse_str(&x).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_timeframe<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(d)?
+        .map(|x| {
+            Timeframe::parse_str(&x)
+                .map(Into::into)
+                .map_err(serde::de::Error::custom)
+        })
+        .transpose()
+}
+
+fn deserialize_header_rules<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<HeaderRule>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|x| HeaderRule::parse_str(x).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn deserialize_rewrite_rules<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Vec<RewriteRule>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|x| RewriteRule::parse_str(x).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_prefix_matches_whole_segments() {
+        assert!(path_matches("/v1", "/v1"));
+        assert!(path_matches("/v1", "/v1/"));
+        assert!(path_matches("/v1", "/v1/users"));
+        assert!(!path_matches("/v1", "/v10"));
+        assert!(!path_matches("/v1", "/v1.json"));
+        assert!(!path_matches("/v1", "/"));
+
+        assert!(path_matches("/v1/", "/v1/users"));
+        assert!(!path_matches("/v1/", "/v1"));
+
+        assert!(path_matches("/", "/"));
+        assert!(path_matches("/", "/anything"));
+    }
+
+    #[test]
+    fn host_matches_with_or_without_port() {
+        assert!(host_matches("api.example.com", "api.example.com"));
+        assert!(host_matches("api.example.com", "API.example.com"));
+        assert!(host_matches("api.example.com", "api.example.com:8000"));
+        assert!(host_matches("api.example.com:8000", "api.example.com:8000"));
+        assert!(!host_matches(
+            "api.example.com:8000",
+            "api.example.com:9000"
+        ));
+        assert!(!host_matches("api.example.com", "api.example.com.evil"));
+        assert!(!host_matches("api.example.com", "evil.api.example.com"));
+
+        assert!(host_matches("[::1]", "[::1]:8000"));
+        assert!(host_matches("127.0.0.1", "127.0.0.1:8000"));
+    }
+}
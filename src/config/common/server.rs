@@ -1,3 +1,7 @@
+use ipnet::IpNet;
+
+use super::proxy::parse_ip_net;
+
 #[derive(Debug, Clone, clap::Args)]
 pub struct ServerConfig {
     /// The port on which the server will listen.
@@ -7,6 +11,21 @@ pub struct ServerConfig {
     /// The host on which the server will listen.
     #[arg(long, default_value = "0.0.0.0", env = "HOST")]
     pub host: String,
+
+    /// Read a PROXY protocol (version 1 or 2) header at the start of connections from these networks.
+    ///
+    /// The source address in the header is used as the address of the connection.
+    /// Connections from these networks without a valid header are closed.
+    ///
+    /// For example, `10.0.0.0/8` or `192.168.1.1,fd00::/8`
+    #[arg(
+        long,
+        value_name = "CIDR",
+        value_delimiter = ',',
+        value_parser = parse_ip_net,
+        env = "CORS_PROXY_PROXY_PROTOCOL"
+    )]
+    pub proxy_protocol: Vec<IpNet>,
 }
 impl ServerConfig {
     pub fn addr_string(&self) -> String {
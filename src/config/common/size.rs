@@ -0,0 +1,36 @@
+/// A number of bytes, eg. `512KiB` or `64MiB`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub usize);
+impl ByteSize {
+    /// Parses a size like `1024`, `512KB` or `64MiB`. Units are powers of 1024.
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+
+        let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+
+        let num = num
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid size {s:?} (eg. 512KiB or 64MiB)"))?;
+
+        let multiplier: usize = match unit.trim().to_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" | "kib" => 1 << 10,
+            "m" | "mb" | "mib" => 1 << 20,
+            "g" | "gb" | "gib" => 1 << 30,
+            x => {
+                return Err(format!(
+                    "Invalid size unit {x:?} (expected B, KiB, MiB or GiB)"
+                ))
+            }
+        };
+
+        num.checked_mul(multiplier)
+            .map(Self)
+            .ok_or_else(|| format!("Size {s:?} is too large"))
+    }
+}
+impl From<ByteSize> for usize {
+    fn from(val: ByteSize) -> Self {
+        val.0
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use super::timeframe::Timeframe;
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Streaming options")]
+pub struct StreamingArgs {
+    /// The content types of streamed responses, like Server-Sent Events,
+    /// either exactly or as `type/*`.
+    ///
+    /// Streamed responses aren't compressed or cached.
+    ///
+    /// For example, `text/event-stream` or `text/event-stream,application/x-ndjson`
+    #[clap(
+        long,
+        value_name = "TYPE",
+        value_delimiter = ',',
+        default_value = "text/event-stream",
+        env = "CORS_PROXY_STREAM_CONTENT_TYPES"
+    )]
+    pub stream_content_types: Vec<String>,
+
+    /// How long a stream may go without data from the upstream, used instead of `--read-timeout`.
+    ///
+    /// It's only known up front that a response will be streamed if the request
+    /// `Accept`s one of `--stream-content-types`, which `EventSource` does.
+    /// Those requests aren't cut off by `--request-timeout` either.
+    ///
+    /// Eg. `5min` or `1h`
+    #[clap(
+        long,
+        value_name = "TIMEOUT",
+        default_value = "10min",
+        value_parser = Timeframe::parse_str,
+        env = "CORS_PROXY_STREAM_IDLE_TIMEOUT"
+    )]
+    pub stream_idle_timeout: Timeframe,
+}
+impl StreamingArgs {
+    pub fn to_config(&self) -> StreamingConfig {
+        StreamingConfig {
+            content_types: self
+                .stream_content_types
+                .iter()
+                .map(|x| x.trim().to_lowercase())
+                .filter(|x| !x.is_empty())
+                .collect(),
+            idle_timeout: self.stream_idle_timeout.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamingConfig {
+    pub content_types: Vec<String>,
+
+    pub idle_timeout: Duration,
+}
+impl StreamingConfig {
+    /// Whether a `Content-Type`, or a media range from `Accept`, is one of a stream
+    pub fn streams_type(&self, content_type: &str) -> bool {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        self.content_types.iter().any(|x| {
+            x.strip_suffix('*')
+                .map_or_else(|| media_type == *x, |prefix| media_type.starts_with(prefix))
+        })
+    }
+}
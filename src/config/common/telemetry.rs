@@ -0,0 +1,37 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OtlpProtocol {
+    /// OTLP over gRPC (usually port 4317)
+    Grpc,
+    /// OTLP over HTTP with protobuf payloads (usually port 4318)
+    Http,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+#[clap(next_help_heading = "Telemetry options")]
+#[allow(clippy::struct_field_names)]
+pub struct TelemetryConfig {
+    /// The OpenTelemetry collector endpoint to export traces to.
+    ///
+    /// Trace exporting is disabled if this is not set.
+    ///
+    /// For example, `http://localhost:4317` or `http://localhost:4318/v1/traces`
+    #[arg(long, value_name = "URL", env = "CORS_PROXY_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// The protocol used to talk to the OpenTelemetry collector.
+    #[arg(
+        long,
+        value_enum,
+        default_value = "grpc",
+        env = "CORS_PROXY_OTLP_PROTOCOL"
+    )]
+    pub otlp_protocol: OtlpProtocol,
+
+    /// The service name reported with exported traces.
+    #[arg(
+        long,
+        default_value = "cors-proxy",
+        env = "CORS_PROXY_OTLP_SERVICE_NAME"
+    )]
+    pub otlp_service_name: String,
+}
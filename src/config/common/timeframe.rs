@@ -35,7 +35,7 @@ impl From<&Timeframe> for String {
     fn from(val: &Timeframe) -> Self {
         let dur: Duration = val.into();
 
-        format!("{mili}ms", mili = dur.as_millis(),)
+        format!("{mili}ms", mili = dur.as_millis())
     }
 }
 
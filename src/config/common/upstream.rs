@@ -0,0 +1,119 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use http::HeaderValue;
+
+/// An upstream address as it was given, eg. `127.0.0.1:80` or `my-computer.local:1234`
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    /// The host name or IP address, without the port
+    pub host: String,
+    pub port: u16,
+    /// The addresses the host resolved to, never empty
+    pub addrs: Vec<SocketAddr>,
+}
+impl Upstream {
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+
+        let (host, port) = s
+            .rsplit_once(':')
+            .filter(|(host, _)| !host.is_empty())
+            .ok_or_else(|| "Address must contain a port (eg. 127.0.0.1:80)".to_string())?;
+
+        let port = port
+            .parse()
+            .map_err(|_| format!("Invalid port {port:?} in address {s:?}"))?;
+
+        let mut addrs = s
+            .to_socket_addrs()
+            .map_err(|e| format!("{e:?}"))?
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err("Failed to parse address".to_string());
+        }
+
+        // In the same order as when the upstream is resolved again
+        addrs.sort_unstable();
+        addrs.dedup();
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            addrs,
+        })
+    }
+
+    /// The first address the host resolved to
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    /// Whether the upstream was given as an IP address rather than a host name
+    pub fn is_ip(&self) -> bool {
+        self.host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok()
+    }
+
+    /// The `Host` header for requests to this upstream.
+    ///
+    /// The port is left out if it's the default one for HTTP or HTTPS.
+    pub fn host_header(&self) -> String {
+        match self.port {
+            80 | 443 => self.host.clone(),
+            port => format!("{}:{port}", self.host),
+        }
+    }
+}
+
+/// Which `Host` header is sent to the upstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamHost {
+    /// Send the `Host` of the client request
+    Preserve,
+    /// Send the name of the upstream as given in `--proxy-to`
+    Upstream,
+    /// Always send this value
+    Fixed(String),
+}
+impl UpstreamHost {
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+
+        match s.to_lowercase().as_str() {
+            "preserve" => Ok(Self::Preserve),
+            "upstream" => Ok(Self::Upstream),
+            "" => Err("Upstream host can't be empty".to_string()),
+            _ => {
+                HeaderValue::from_str(s).map_err(|e| format!("Invalid host {s:?}: {e}"))?;
+
+                Ok(Self::Fixed(s.to_string()))
+            }
+        }
+    }
+}
+
+/// Which HTTP versions are used to talk to the upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamProtocol {
+    /// HTTP/1.1 only
+    Http1,
+    /// HTTP/2 if the upstream offers it in the TLS handshake (ALPN), otherwise HTTP/1.1
+    Http2,
+    /// HTTP/2 without TLS, assuming the upstream speaks it (prior knowledge)
+    H2c,
+}
+impl UpstreamProtocol {
+    pub fn parse_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "http1" | "http/1.1" | "h1" => Ok(Self::Http1),
+            "http2" | "h2" => Ok(Self::Http2),
+            "h2c" => Ok(Self::H2c),
+            x => Err(format!(
+                "Invalid upstream protocol {x:?} (expected http1, h2 or h2c)"
+            )),
+        }
+    }
+}
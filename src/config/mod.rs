@@ -1,25 +1,40 @@
-use clap::Parser;
-use once_cell::sync::Lazy;
+use std::sync::LazyLock;
+
+use clap::{CommandFactory, Parser};
 
 use self::{
     args::Args,
-    common::{pingora::PingoraConfig, proxy::ProxyConfig, server::ServerConfig},
+    common::{
+        access_log::AccessLogConfig, metrics::MetricsConfig, pingora::PingoraConfig,
+        proxy::ProxyConfig, server::ServerConfig, telemetry::TelemetryConfig,
+    },
 };
 
 pub mod args;
 pub mod common;
 
-pub static CONFIG: Lazy<Config> = Lazy::new(Config::new);
+pub static CONFIG: LazyLock<Config> = LazyLock::new(Config::new);
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub pingora: PingoraConfig,
     pub proxy: ProxyConfig,
     pub server: ServerConfig,
+    pub metrics: MetricsConfig,
+    pub telemetry: TelemetryConfig,
+    pub access_log: AccessLogConfig,
 }
 impl Config {
     fn new() -> Self {
-        Self::from_args(Args::parse())
+        let config = Self::from_args(Args::parse());
+
+        if let Err(e) = config.proxy.check_upstreams() {
+            Args::command()
+                .error(clap::error::ErrorKind::ValueValidation, e)
+                .exit();
+        }
+
+        config
     }
 
     fn from_args(args: Args) -> Self {
@@ -27,6 +42,9 @@ impl Config {
             pingora: args.pingora,
             proxy: args.proxy.to_config(),
             server: args.server,
+            metrics: args.metrics,
+            telemetry: args.telemetry,
+            access_log: args.access_log,
         }
     }
 }
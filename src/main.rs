@@ -1,15 +1,28 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+use access_log::AccessLog;
 use config::CONFIG;
-use pingora::{prelude::*, server::configuration::ServerConf, services::Service};
-use services::add_cors_headers::AddCorsHeaders;
-use tracing::{debug, info};
-
+use pingora::{
+    prelude::*,
+    proxy::HttpProxy,
+    server::configuration::ServerConf,
+    services::{background::background_service, listening::Service as ListeningService, Service},
+};
+use services::{
+    add_cors_headers::AddCorsHeaders,
+    connections::Connections,
+    resolve_upstreams::{UpstreamAddrs, UpstreamResolver},
+};
+use tracing::{debug, info, warn};
+
+mod access_log;
 mod config;
+mod metrics;
 mod services;
+mod telemetry;
 
 fn main() {
-    init_log();
+    let exporter = init_log();
 
     info!("Starting server");
     debug!(config=?*CONFIG, "Starting server");
@@ -22,18 +35,63 @@ fn main() {
 
     server.bootstrap();
 
-    let services = vec![proxy_service(&server.configuration)];
+    let upstream_addrs = UpstreamAddrs::new(&CONFIG.proxy);
+
+    let mut services = vec![proxy_service(&server.configuration, upstream_addrs.clone())];
+
+    if let Some(resolver) = UpstreamResolver::new(upstream_addrs, &CONFIG.proxy) {
+        services.push(resolver_service(resolver));
+    }
+
+    if let Some(addr) = CONFIG.metrics.addr_string() {
+        services.push(metrics_service(&addr));
+    }
+
+    if let Some(exporter) = exporter {
+        services.push(Box::new(background_service("OTLP exporter", exporter)));
+    }
 
     server.add_services(services);
     server.run_forever();
 }
 
-fn proxy_service(conf: &Arc<ServerConf>) -> Box<dyn Service> {
+fn proxy_service(conf: &Arc<ServerConf>, upstream_addrs: UpstreamAddrs) -> Box<dyn Service> {
     let config = CONFIG.proxy.clone();
     let threads = num_cpus::get();
 
+    let access_log = CONFIG.access_log.access_log_format.map(|format| {
+        let path = &CONFIG.access_log.access_log_file;
+
+        info!(?format, ?path, "Writing access log");
+        AccessLog::new(format, path).expect("Failed to open access log")
+    });
+
+    if (!config.client_allowlist.is_empty() || !config.client_denylist.is_empty())
+        && config.trusted_proxies.is_empty()
+        && CONFIG.server.proxy_protocol.is_empty()
+    {
+        warn!(
+            "Client access lists are checked against connection addresses since neither \
+             --trusted-proxies nor --proxy-protocol is set. Behind a load balancer, \
+             every request comes from its address"
+        );
+    }
+
+    let keepalive_timeout = config.downstream.keepalive_timeout.map(Duration::from_secs);
+
     debug!(?config, ?threads, "Creating proxy service");
-    let mut service = pingora::proxy::http_proxy_service(conf, AddCorsHeaders::new(config));
+    let proxy = HttpProxy::new(
+        AddCorsHeaders::new(config, access_log, upstream_addrs),
+        conf.clone(),
+    );
+    let mut service = ListeningService::new(
+        "Pingora HTTP Proxy Service".into(),
+        Arc::new(Connections::new(
+            proxy,
+            CONFIG.server.proxy_protocol.clone(),
+            keepalive_timeout,
+        )),
+    );
     service.threads = Some(threads);
 
     let addr = CONFIG.server.addr_string();
@@ -43,7 +101,25 @@ fn proxy_service(conf: &Arc<ServerConf>) -> Box<dyn Service> {
     Box::new(service)
 }
 
-fn init_log() {
+fn resolver_service(resolver: UpstreamResolver) -> Box<dyn Service> {
+    info!(
+        interval = ?CONFIG.proxy.upstream_resolve_interval,
+        "Adding upstream resolver service"
+    );
+
+    Box::new(background_service("upstream resolver", resolver))
+}
+
+fn metrics_service(addr: &str) -> Box<dyn Service> {
+    let mut service = ListeningService::prometheus_http_service();
+
+    info!(?addr, "Adding listener for metrics service");
+    service.add_tcp(addr);
+
+    Box::new(service)
+}
+
+fn init_log() -> Option<telemetry::Exporter> {
     use tracing::Level;
     use tracing_subscriber::{
         filter::Directive, fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
@@ -70,9 +146,14 @@ fn init_log() {
         base_level = base_level.add_directive(d);
     }
 
+    let (telemetry, exporter) = telemetry::init(&CONFIG.telemetry);
+
     tracing_subscriber::registry()
+        .with(telemetry)
         .with(fmt::layer())
         .with(base_level)
         .try_init()
         .expect("setting default subscriber failed");
+
+    exporter
 }
@@ -0,0 +1,110 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+pub static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "cors_proxy_requests_total",
+        "Number of requests handled by the proxy",
+        &["method", "status", "route"]
+    )
+    .expect("Failed to register requests metric")
+});
+
+pub static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "cors_proxy_request_duration_seconds",
+        "Time from receiving a request until it is done being handled",
+        &["method", "status", "route"]
+    )
+    .expect("Failed to register request duration metric")
+});
+
+pub static UPSTREAM_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "cors_proxy_upstream_latency_seconds",
+        "Time from sending a request upstream until the response headers arrive",
+        &["route"]
+    )
+    .expect("Failed to register upstream latency metric")
+});
+
+pub static PREFLIGHTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "cors_proxy_preflight_requests_total",
+        "Number of CORS preflight requests answered"
+    )
+    .expect("Failed to register preflight metric")
+});
+
+pub static REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "cors_proxy_rejections_total",
+        "Number of requests that were rejected or not given CORS headers",
+        &["reason"]
+    )
+    .expect("Failed to register rejections metric")
+});
+
+pub static TLS_FALLBACKS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "cors_proxy_tls_fallbacks_total",
+        "Number of times the upstream connection fell back from TLS to plain HTTP"
+    )
+    .expect("Failed to register TLS fallback metric")
+});
+
+pub static RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "cors_proxy_retries_total",
+        "Number of upstream requests that were retried, by what failed",
+        &["reason"]
+    )
+    .expect("Failed to register retries metric")
+});
+
+pub static CACHE_RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "cors_proxy_cache_responses_total",
+        "Number of responses by their X-Cache status",
+        &["status"]
+    )
+    .expect("Failed to register cache responses metric")
+});
+
+pub static CIRCUIT_BREAKER_TRANSITIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "cors_proxy_circuit_breaker_transitions_total",
+        "Number of times a circuit changed to a state, by upstream address or `open_proxy`",
+        &["upstream", "state"]
+    )
+    .expect("Failed to register circuit breaker transitions metric")
+});
+
+pub static CIRCUIT_BREAKER_STATE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "cors_proxy_circuit_breaker_state",
+        "The circuit state of an upstream address: 0 closed, 1 open, 2 half-open",
+        &["upstream"]
+    )
+    .expect("Failed to register circuit breaker state metric")
+});
+
+pub static ACTIVE_REQUESTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "cors_proxy_active_requests",
+        "Number of requests currently being handled by the proxy"
+    )
+    .expect("Failed to register active requests metric")
+});
+
+pub static ACTIVE_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "cors_proxy_active_connections",
+        "Number of client connections currently open"
+    )
+    .expect("Failed to register active connections metric")
+});
@@ -1,24 +1,599 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock,
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use http::{header, Method};
-use once_cell::sync::Lazy;
-use pingora::{http::ResponseHeader, prelude::*};
+use bytes::Bytes;
+use http::{header, HeaderMap, Method};
+use pingora::{
+    cache::{key::HashBinary, CacheKey, CacheMeta, NoCacheReason, RespCacheable},
+    http::ResponseHeader,
+    prelude::*,
+    protocols::{
+        http::{client::HttpSession as UpstreamSession, error_resp, v1::server::HttpSession},
+        ALPN,
+    },
+    upstreams::peer::{Peer, PeerOptions},
+};
+use tokio::sync::OwnedSemaphorePermit;
 use tracing::{debug, field, info, trace, warn};
-
-use crate::config::common::proxy::ProxyConfig;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use self::{
+    cache::ResponseCache,
+    circuit_breaker::{CircuitBreaker, Label, Permit},
+    concurrency::ConcurrencyLimiter,
+    open_proxy::Target,
+    rate_limit::{RateLimitState, RateLimiter},
+    retry::RetryPolicy,
+};
+use crate::{
+    access_log::{AccessLog, AccessLogEntry},
+    config::common::{
+        compression::Encoding,
+        headers::{HeaderRule, TemplateVar},
+        proxy::{ProxyConfig, UpstreamTimeouts},
+        retry::RetryCondition,
+        routes::{RouteConfig, DEFAULT_ROUTE},
+        upstream::{Upstream, UpstreamHost, UpstreamProtocol},
+    },
+    metrics,
+    services::{connections, resolve_upstreams::UpstreamAddrs},
+    telemetry,
+};
+
+mod cache;
+mod circuit_breaker;
+mod compression;
+mod concurrency;
+mod forwarded;
+mod grpc_web;
+mod header_rules;
+mod open_proxy;
+mod rate_limit;
+mod retry;
+mod websocket;
 
 #[derive(Debug)]
 pub struct AddCorsHeaders {
     config: ProxyConfig,
     use_tls: AtomicBool,
+    access_log: Option<AccessLog>,
+    rate_limiter: Option<RateLimiter>,
+    concurrency_limiter: Option<ConcurrencyLimiter>,
+    /// The concurrency limiters of the configured routes, by route index
+    route_limiters: Vec<Option<ConcurrencyLimiter>>,
+    upstream_addrs: UpstreamAddrs,
+    cache: Option<ResponseCache>,
+    retry_policy: Option<RetryPolicy>,
+    circuit_breaker: Option<CircuitBreaker>,
+    /// Open proxy destinations get circuits of their own, which aren't labelled by address in metrics
+    open_proxy_circuit_breaker: Option<CircuitBreaker>,
 }
 impl AddCorsHeaders {
-    pub fn new(config: ProxyConfig) -> Self {
+    pub fn new(
+        config: ProxyConfig,
+        access_log: Option<AccessLog>,
+        upstream_addrs: UpstreamAddrs,
+    ) -> Self {
+        let rate_limiter = config.rate_limit.clone().map(RateLimiter::new);
+        let concurrency_limiter = config.concurrency.clone().map(ConcurrencyLimiter::new);
+        let route_limiters = config
+            .routes
+            .iter()
+            .map(|x| x.concurrency.clone().map(ConcurrencyLimiter::new))
+            .collect();
+        let cache = config.cache.clone().map(ResponseCache::new);
+        let retry_policy = config.retry.clone().map(RetryPolicy::new);
+        let circuit_breaker = config
+            .circuit_breaker
+            .clone()
+            .map(|x| CircuitBreaker::new(x, Label::Addr));
+        let open_proxy_circuit_breaker = config
+            .circuit_breaker
+            .clone()
+            .filter(|_| config.open_proxy.is_some())
+            .map(|x| CircuitBreaker::new(x, Label::Fixed("open_proxy")));
+
         Self {
             config,
             use_tls: AtomicBool::new(true),
+            access_log,
+            rate_limiter,
+            concurrency_limiter,
+            route_limiters,
+            upstream_addrs,
+            cache,
+            retry_policy,
+            circuit_breaker,
+            open_proxy_circuit_breaker,
+        }
+    }
+
+    fn route_config(&self, route: Option<usize>) -> Option<&RouteConfig> {
+        route.and_then(|x| self.config.routes.get(x))
+    }
+
+    fn route_name(&self, route: Option<usize>) -> &str {
+        self.route_config(route)
+            .map_or(DEFAULT_ROUTE, |x| x.name.as_str())
+    }
+
+    fn upstream_timeouts(&self, route: Option<usize>) -> UpstreamTimeouts {
+        self.route_config(route)
+            .map_or(self.config.upstream_timeouts, |x| x.upstream_timeouts)
+    }
+
+    fn upstream_protocol(&self, route: Option<usize>, ctx: &AddCorsHeadersCtx) -> UpstreamProtocol {
+        let protocol = self
+            .route_config(route)
+            .and_then(|x| x.upstream_protocol)
+            .unwrap_or(self.config.upstream_protocol);
+
+        if ctx.websocket {
+            // Pingora only passes upgraded connections through over HTTP/1.1
+            UpstreamProtocol::Http1
+        } else if ctx.grpc_web.is_some() && protocol == UpstreamProtocol::Http1 {
+            // gRPC needs HTTP/2
+            UpstreamProtocol::H2c
+        } else {
+            protocol
+        }
+    }
+
+    fn grpc_web(&self, route: Option<usize>) -> bool {
+        self.route_config(route)
+            .and_then(|x| x.grpc_web)
+            .unwrap_or(self.config.grpc_web)
+    }
+
+    /// Set which HTTP versions a peer may use
+    const fn set_peer_protocol(&self, options: &mut PeerOptions, protocol: UpstreamProtocol, tls: bool) {
+        options.alpn = match protocol {
+            UpstreamProtocol::Http1 => ALPN::H1,
+            // Without TLS there's nothing to negotiate with, so Pingora sticks to HTTP/1.1
+            UpstreamProtocol::Http2 => ALPN::H2H1,
+            // Only open proxy targets can still use TLS, where HTTP/2 is negotiated as usual
+            UpstreamProtocol::H2c if tls => ALPN::H2H1,
+            UpstreamProtocol::H2c => ALPN::H2,
+        };
+        options.max_h2_streams = self.config.upstream_h2_max_streams;
+    }
+
+    /// Set the timeouts of a peer, shortened so none of them runs past the request deadline
+    fn set_peer_timeouts(&self, options: &mut PeerOptions, ctx: &AddCorsHeadersCtx) -> Result<()> {
+        let timeouts = self.upstream_timeouts(ctx.route);
+
+        options.connection_timeout = Some(self.config.connection_timeout);
+        options.total_connection_timeout = Some(self.config.total_connection_timeout);
+        options.idle_timeout = self.config.idle_timeout;
+        options.read_timeout = if ctx.stream {
+            Some(self.config.streaming.idle_timeout)
+        } else {
+            timeouts.read
+        };
+        options.write_timeout = timeouts.write;
+
+        if let Some(deadline) = ctx.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return deadline_exceeded();
+            }
+
+            for timeout in [
+                &mut options.connection_timeout,
+                &mut options.total_connection_timeout,
+                &mut options.read_timeout,
+                &mut options.write_timeout,
+            ] {
+                *timeout = Some(timeout.map_or(remaining, |x| x.min(remaining)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn upstream(&self, route: Option<usize>) -> &Upstream {
+        self.route_config(route)
+            .and_then(|x| x.proxy_to.as_ref())
+            .unwrap_or(&self.config.proxy_to)
+    }
+
+    /// The `Host` header to send to the upstream
+    fn upstream_host_header(&self, session: &Session, route: Option<usize>) -> Option<String> {
+        let mode = self
+            .route_config(route)
+            .and_then(|x| x.upstream_host.as_ref())
+            .unwrap_or(&self.config.upstream_host);
+
+        match mode {
+            UpstreamHost::Preserve => session
+                .get_header(header::HOST)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_string),
+            UpstreamHost::Upstream => Some(self.upstream(route).host_header()),
+            UpstreamHost::Fixed(host) => Some(host.clone()),
+        }
+    }
+
+    /// Apply the first matching rewrite rule to the upstream request
+    fn rewrite_path(
+        &self,
+        upstream_request: &mut RequestHeader,
+        route: Option<usize>,
+    ) -> Result<()> {
+        let route_rules = self
+            .route_config(route)
+            .map(|x| x.rewrites.as_slice())
+            .unwrap_or_default();
+
+        let original = upstream_request
+            .uri
+            .path_and_query()
+            .map_or("/", http::uri::PathAndQuery::as_str);
+
+        let Some(rewritten) = route_rules
+            .iter()
+            .chain(&self.config.rewrites)
+            .find_map(|rule| rule.apply(original))
+        else {
+            trace!(path = original, "No rewrite rule matched");
+            return Ok(());
+        };
+
+        debug!(original, rewritten, "Rewrote upstream path");
+
+        let uri = rewritten
+            .parse::<http::Uri>()
+            .or_err_with(InternalError, || {
+                format!("Rewrite produced an invalid path {rewritten:?}")
+            })?;
+        upstream_request.set_uri(uri);
+
+        Ok(())
+    }
+
+    /// Check the `Host` header against the allowlist, responding with an error if it's not allowed.
+    ///
+    /// Returns whether the request was rejected.
+    async fn rejected_by_host_allowlist(&self, session: &mut Session) -> bool {
+        let allowlist = &self.config.host_allowlist;
+
+        if allowlist.is_empty() {
+            trace!("Host allowlist is empty");
+            return false;
+        }
+
+        let request_host = session
+            .get_header("Host")
+            .ok_or_else(|| {
+                pingora::Error::explain(ErrorType::UnknownError, "Missing Host header").into_down()
+            })
+            .and_then(|x| {
+                x.to_str().map_err(|e| {
+                    warn!(?e, "Failed to parse Host header");
+
+                    pingora::Error::because(
+                        ErrorType::UnknownError,
+                        "Failed to parse Host header",
+                        e,
+                    )
+                    .into_in()
+                })
+            })
+            .map(str::to_lowercase);
+
+        let request_host = match request_host {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(?e, "Failed to parse Host header");
+
+                session
+                    .respond_error(http::StatusCode::BAD_REQUEST.as_u16())
+                    .await;
+
+                return true;
+            }
+        };
+
+        debug!(host = ?request_host, "Got host header");
+
+        if !allowlist.contains(&request_host) {
+            debug!(
+                host = ?request_host,
+                ?allowlist,
+                "Host header not in allowlist"
+            );
+
+            info!(host = ?request_host, "Host header not in allowlist");
+            metrics::REJECTIONS.with_label_values(&["host"]).inc();
+
+            session
+                .respond_error(http::StatusCode::BAD_REQUEST.as_u16())
+                .await;
+
+            return true;
+        }
+
+        false
+    }
+
+    /// The circuit breaker of open proxy destinations or of the configured upstreams
+    const fn circuit_breaker(&self, open_proxy: bool) -> Option<&CircuitBreaker> {
+        if open_proxy {
+            self.open_proxy_circuit_breaker.as_ref()
+        } else {
+            self.circuit_breaker.as_ref()
+        }
+    }
+
+    /// The first of the upstream addresses whose circuit isn't open.
+    ///
+    /// Rejects the request if every circuit is open.
+    fn pick_upstream_addr(
+        &self,
+        addrs: &[SocketAddr],
+        open_proxy: bool,
+        circuit: &mut Option<Permit>,
+    ) -> Result<SocketAddr> {
+        let Some(circuit_breaker) = self.circuit_breaker(open_proxy) else {
+            return addrs
+                .first()
+                .copied()
+                .ok_or_else(|| Error::explain(InternalError, "No upstream address"));
+        };
+
+        let Some(permit) = addrs.iter().find_map(|x| circuit_breaker.allow(*x)) else {
+            info!(?addrs, "Circuit open, rejecting request");
+            metrics::REJECTIONS
+                .with_label_values(&["circuit_open"])
+                .inc();
+
+            return Error::e_explain(
+                HTTPStatus(http::StatusCode::SERVICE_UNAVAILABLE.as_u16()),
+                "Upstream circuit open",
+            );
+        };
+
+        *circuit = Some(permit);
+
+        Ok(permit.addr)
+    }
+
+    /// Record the outcome of the request to the upstream in its circuit breaker
+    fn record_circuit(&self, circuit: &mut Option<Permit>, open_proxy: bool, success: bool) {
+        if let (Some(circuit_breaker), Some(permit)) =
+            (self.circuit_breaker(open_proxy), circuit.take())
+        {
+            circuit_breaker.record(permit, success);
+        }
+    }
+
+    /// Give up the request to the upstream in its circuit breaker without an outcome
+    fn release_circuit(&self, circuit: &mut Option<Permit>, open_proxy: bool) {
+        if let (Some(circuit_breaker), Some(permit)) =
+            (self.circuit_breaker(open_proxy), circuit.take())
+        {
+            circuit_breaker.release(permit);
+        }
+    }
+
+    /// How long to wait before retrying a failed attempt, or `None` if it shouldn't be retried
+    fn retry_backoff(
+        &self,
+        session: &Session,
+        retries: usize,
+        condition: RetryCondition,
+    ) -> Option<Duration> {
+        let policy = self.retry_policy.as_ref()?;
+
+        // The request body can only be sent again if it fit in the retry buffer
+        if session.as_ref().retry_buffer_truncated()
+            || !policy.should_retry(&session.req_header().method, retries, condition)
+        {
+            return None;
+        }
+
+        let backoff = policy.backoff(retries + 1);
+        info!(retry = retries + 1, ?condition, ?backoff, "Retrying upstream request");
+        metrics::RETRIES
+            .with_label_values(&[&condition.as_label()])
+            .inc();
+
+        Some(backoff)
+    }
+
+    /// Compress or decompress the response for the client, depending on the config.
+    ///
+    /// Returns the encoding the response may be compressed with.
+    fn enable_compression(
+        &self,
+        session: &mut Session,
+        ctx: &AddCorsHeadersCtx,
+    ) -> Result<Option<Encoding>> {
+        // gRPC messages are compressed by gRPC itself, and WebSocket messages aren't HTTP bodies
+        if ctx.grpc_web.is_some() || ctx.websocket {
+            return Ok(None);
+        }
+
+        let encoding = match &self.config.compression {
+            Some(config) => compression::enable(config, session)?,
+            None => None,
+        };
+
+        if self.config.decompression {
+            session.downstream_compression.adjust_decompression(true);
+        }
+
+        Ok(encoding)
+    }
+
+    /// Set how long the client connection is kept alive for after the request.
+    ///
+    /// Pingora keeps connections alive for 60s, except when the server is shutting down.
+    /// How long they're kept idle is enforced by `Connections`.
+    fn set_keepalive(&self, session: &mut Session) {
+        if session.as_http1().is_some_and(HttpSession::will_keepalive) {
+            session.set_keepalive(self.config.downstream.keepalive_timeout);
+        }
+    }
+
+    /// The status and reason to reject the request with if its headers or body are too large
+    fn oversized(&self, session: &Session) -> Option<(http::StatusCode, &'static str)> {
+        let limits = &self.config.downstream;
+
+        if limits
+            .max_header_size
+            .is_some_and(|max| session.as_ref().to_h1_raw().len() > max)
+        {
+            return Some((
+                http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "header_too_large",
+            ));
+        }
+
+        // Upgraded connections have no body, but Pingora reads them as one until they're closed
+        if session.is_upgrade_req() {
+            return None;
+        }
+
+        let max_body_size = limits.max_body_size?;
+
+        // Chunked bodies could only be counted while they're proxied, which Pingora doesn't allow,
+        // so they're let through
+        let len = session
+            .get_header(header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.trim().parse::<usize>().ok())?;
+
+        (len > max_body_size).then_some((http::StatusCode::PAYLOAD_TOO_LARGE, "body_too_large"))
+    }
+
+    /// Take a token from the client's rate limit bucket.
+    ///
+    /// Returns the rate limit state if the request should be rejected.
+    fn rate_limited(
+        &self,
+        session: &Session,
+        client_addr: Option<IpAddr>,
+    ) -> Option<RateLimitState> {
+        let rate_limiter = self.rate_limiter.as_ref()?;
+
+        let Some(key) = rate_limiter.key(session, client_addr) else {
+            trace!("Request has no rate limit key, not rate limiting");
+            return None;
+        };
+
+        let state = rate_limiter.check(&key);
+        if state.allowed {
+            return None;
+        }
+
+        info!(?key, retry_after = ?state.retry_after, "Rate limited");
+        metrics::REJECTIONS.with_label_values(&["rate_limit"]).inc();
+
+        Some(state)
+    }
+
+    /// The status and reason to reject the request with, if it can't be proxied
+    fn rejection(
+        &self,
+        session: &Session,
+        websocket: bool,
+    ) -> Option<(http::StatusCode, &'static str)> {
+        self.oversized(session).or_else(|| {
+            // Browsers don't check CORS for WebSockets, so the origin has to be checked here
+            (websocket && !self.websocket_origin_allowed(session))
+                .then_some((http::StatusCode::FORBIDDEN, "websocket_origin"))
+        })
+    }
+
+    /// Check the `Origin` of a WebSocket handshake against the allowlist.
+    ///
+    /// Like for CORS, handshakes without an `Origin` are only allowed when there's no allowlist.
+    fn websocket_origin_allowed(&self, session: &Session) -> bool {
+        let origin = session
+            .get_header(header::ORIGIN)
+            .and_then(|x| x.to_str().ok())
+            .map(str::to_lowercase);
+
+        let allowed = self.origin_allowed(origin.as_deref());
+        if !allowed {
+            info!(?origin, "WebSocket origin not in allowlist");
+        }
+
+        allowed
+    }
+
+    /// The address of the client, taken from the forwarding headers if the peer is a trusted proxy
+    fn client_addr(&self, session: &Session, peer_addr: Option<IpAddr>) -> Option<IpAddr> {
+        forwarded::client_addr(
+            &session.req_header().headers,
+            peer_addr,
+            &self.config.trusted_proxies,
+        )
+    }
+
+    /// Whether the client address is rejected by the client access lists
+    fn client_rejected(&self, client_addr: Option<IpAddr>) -> bool {
+        let in_list = |list: &[ipnet::IpNet]| {
+            client_addr.is_some_and(|addr| list.iter().any(|net| net.contains(&addr)))
+        };
+
+        let list = if in_list(&self.config.client_denylist) {
+            "denylist"
+        } else if !self.config.client_allowlist.is_empty()
+            && !in_list(&self.config.client_allowlist)
+        {
+            "allowlist"
+        } else {
+            return false;
+        };
+
+        info!(client = ?client_addr, list, "Client address not allowed");
+        metrics::REJECTIONS
+            .with_label_values(&[&format!("client_{list}")])
+            .inc();
+
+        true
+    }
+
+    /// Wait for a free slot under the global and route concurrency limits
+    async fn acquire_upstream_slots(
+        &self,
+        route: Option<usize>,
+        permits: &mut Vec<OwnedSemaphorePermit>,
+    ) -> Result<()> {
+        let limiters = [
+            self.concurrency_limiter.as_ref(),
+            route
+                .and_then(|x| self.route_limiters.get(x))
+                .and_then(Option::as_ref),
+        ];
+
+        for limiter in limiters.into_iter().flatten() {
+            match limiter.acquire().await {
+                Ok(permit) => permits.push(permit),
+                Err(rejection) => {
+                    info!(?rejection, "Concurrency limit reached");
+                    metrics::REJECTIONS
+                        .with_label_values(&[rejection.as_str()])
+                        .inc();
+
+                    return Error::e_explain(
+                        ErrorType::HTTPStatus(http::StatusCode::SERVICE_UNAVAILABLE.as_u16()),
+                        "Concurrency limit reached",
+                    );
+                }
+            }
         }
+
+        Ok(())
     }
 
     fn using_tls(&self) -> bool {
@@ -38,32 +613,473 @@ impl AddCorsHeaders {
         debug!("Setting use_tls to {}", use_tls);
         self.use_tls.store(use_tls, Ordering::Relaxed);
 
-        use_tls
+        use_tls
+    }
+
+    /// The value of a template variable for the request
+    fn template_value(&self, session: &Session, info: &RequestInfo, var: TemplateVar) -> String {
+        match var {
+            TemplateVar::ClientIp => info
+                .client_addr
+                .map_or_else(|| "unknown".to_string(), |x| x.to_string()),
+            TemplateVar::RequestId => info.request_id.to_string(),
+            TemplateVar::Route => self.route_name(info.route).to_string(),
+            TemplateVar::Host => session
+                .get_header(header::HOST)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+            TemplateVar::Method => session.req_header().method.to_string(),
+            TemplateVar::Path => session.req_header().uri.path().to_string(),
+        }
+    }
+
+    /// The global header rules followed by the ones of the route
+    fn header_rules<'a>(
+        &'a self,
+        route: Option<usize>,
+        rules: impl Fn(&'a RouteConfig) -> &'a [HeaderRule],
+        global: &'a [HeaderRule],
+    ) -> impl Iterator<Item = &'a HeaderRule> {
+        let route = self.route_config(route).map(rules).unwrap_or_default();
+
+        global.iter().chain(route)
+    }
+
+    /// Add the request ID, header rules and CORS headers to a response going to the client.
+    ///
+    /// Returns whether the CORS headers were added.
+    fn add_response_headers(
+        &self,
+        session: &Session,
+        response: &mut ResponseHeader,
+        info: &RequestInfo,
+    ) -> Result<bool> {
+        response.insert_header(self.config.request_id_header.clone(), info.request_id)?;
+
+        header_rules::apply(
+            response,
+            self.header_rules(
+                info.route,
+                |x| &x.response_headers,
+                &self.config.response_headers,
+            ),
+            |var| self.template_value(session, info, var),
+        )?;
+
+        self.add_cors_headers(session, response)
+    }
+
+    /// Whether the client asks for a streamed response, eg. with `EventSource`
+    fn accepts_stream(&self, session: &Session) -> bool {
+        session
+            .get_header(header::ACCEPT)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(|x| x.split(',').any(|x| self.config.streaming.streams_type(x)))
+    }
+
+    /// Whether the response is streamed, eg. Server-Sent Events
+    fn is_stream(&self, response: &ResponseHeader) -> bool {
+        response
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(|x| self.config.streaming.streams_type(x))
+    }
+
+    /// Whether the lowercased origin is in the allowlist, if there is one
+    fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        self.config.origin_allowlist.is_empty()
+            || origin.is_some_and(|x| self.config.origin_allowlist.contains(x))
+    }
+
+    /// Returns whether the CORS headers were added
+    fn add_cors_headers(&self, session: &Session, response: &mut ResponseHeader) -> Result<bool> {
+        let mut vary_headers = vec![header::ORIGIN.to_string()];
+
+        let origin = session.get_header(header::ORIGIN);
+        let origin_str = origin.and_then(|x| x.to_str().ok()).map(str::to_lowercase);
+
+        trace!(?origin, ?response, "Starting response filter");
+
+        if !self.origin_allowed(origin_str.as_deref()) {
+            debug!(origin = ?origin, "Origin not in allowlist, not adding CORS headers");
+            metrics::REJECTIONS.with_label_values(&["origin"]).inc();
+
+            return Ok(false);
+        }
+
+        {
+            let upstream_headers = response
+                .headers
+                .iter()
+                .map(|x| x.0.to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+
+            trace!(
+                headers = ?upstream_headers,
+                "Allowing all upstream headers",
+            );
+
+            response.insert_header(header::ACCESS_CONTROL_EXPOSE_HEADERS, upstream_headers)?;
+        }
+
+        // response.insert_header(header::ACCESS_CONTROL_MAX_AGE, "1")?;
+
+        if let Some(origin) = origin_str {
+            debug!(origin = ?origin, "Adding origin-specific CORS headers");
+            response.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)?;
+            response.insert_header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
+        } else {
+            debug!("Adding generic CORS headers");
+            response.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")?;
+        }
+
+        if let Some(header) = session.get_header(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            trace!(?header, "Adding Access-Control-Request-Headers header");
+            vary_headers.push(header::ACCESS_CONTROL_REQUEST_HEADERS.to_string());
+            response.insert_header(header::ACCESS_CONTROL_ALLOW_HEADERS, header)?;
+        } else {
+            response.insert_header(header::ACCESS_CONTROL_ALLOW_HEADERS, "*")?;
+        }
+
+        if let Some(header) = session.get_header(header::ACCESS_CONTROL_REQUEST_METHOD) {
+            trace!(?header, "Adding Access-Control-Request-Method header");
+            vary_headers.push(header::ACCESS_CONTROL_REQUEST_METHOD.to_string());
+            response.insert_header(header::ACCESS_CONTROL_ALLOW_METHODS, header)?;
+        } else {
+            response.insert_header(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                HTTP_METHODS_STR.clone(),
+            )?;
+        }
+
+        response.append_header(header::VARY, vary_headers.join(", "))?;
+
+        if session.req_header().method == Method::OPTIONS
+            && session
+                .get_header(header::ACCESS_CONTROL_REQUEST_METHOD)
+                .is_some()
+        {
+            metrics::PREFLIGHTS.inc();
+        }
+
+        Ok(true)
+    }
+
+    /// Proxy a gRPC-Web text request, whose bodies Pingora can't translate.
+    ///
+    /// The request body is decoded before it's sent, and the response body is encoded as it comes,
+    /// ending with the trailers. Otherwise the request goes through the same steps as others,
+    /// including retries.
+    async fn proxy_grpc_web_text(
+        &self,
+        session: &mut Session,
+        ctx: &mut AddCorsHeadersCtx,
+    ) -> Result<()> {
+        let body = self.read_grpc_web_text(session).await?;
+
+        loop {
+            let peer = self.upstream_peer(session, ctx).await?;
+
+            match self
+                .grpc_web_text_attempt(session, ctx, &peer, body.clone())
+                .await
+            {
+                Err(e)
+                    if e.retry()
+                        && ctx.attempts < MAX_ATTEMPTS
+                        && session.response_written().is_none() => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Read the whole body of a gRPC-Web text request and decode it
+    async fn read_grpc_web_text(&self, session: &mut Session) -> Result<Bytes> {
+        let mut body = Vec::new();
+        while let Some(chunk) = session.read_request_body().await? {
+            body.extend_from_slice(&chunk);
+
+            if self
+                .config
+                .downstream
+                .max_body_size
+                .is_some_and(|max| body.len() > max)
+            {
+                metrics::REJECTIONS
+                    .with_label_values(&["body_too_large"])
+                    .inc();
+
+                return Error::e_explain(
+                    HTTPStatus(http::StatusCode::PAYLOAD_TOO_LARGE.as_u16()),
+                    "gRPC-Web text request body too large",
+                );
+            }
+        }
+
+        grpc_web::decode_text(&body)
+    }
+
+    /// Send a gRPC-Web text request to the upstream once
+    async fn grpc_web_text_attempt(
+        &self,
+        session: &mut Session,
+        ctx: &mut AddCorsHeadersCtx,
+        peer: &HttpPeer,
+        body: Bytes,
+    ) -> Result<()> {
+        let (mut upstream, reused) = match grpc_web::CONNECTOR.get_http_session(peer).await {
+            Ok(x) => x,
+            Err(e) => return Err(self.fail_to_connect(session, peer, ctx, e)),
+        };
+
+        if let Some(timeout) = peer.options.read_timeout {
+            upstream.set_read_timeout(timeout);
+        }
+        if let Some(timeout) = peer.options.write_timeout {
+            upstream.set_write_timeout(timeout);
+        }
+
+        match self
+            .grpc_web_text_exchange(session, ctx, &mut upstream, body)
+            .await
+        {
+            Ok(()) => {
+                grpc_web::CONNECTOR
+                    .release_http_session(upstream, peer, peer.idle_timeout())
+                    .await;
+
+                Ok(())
+            }
+            Err(e) => {
+                upstream.shutdown().await;
+
+                Err(self.error_while_proxy(peer, session, e, ctx, reused))
+            }
+        }
+    }
+
+    /// Send the decoded request to the upstream, and its encoded response to the client
+    async fn grpc_web_text_exchange(
+        &self,
+        session: &mut Session,
+        ctx: &mut AddCorsHeadersCtx,
+        upstream: &mut UpstreamSession,
+        body: Bytes,
+    ) -> Result<()> {
+        let mut req = session.req_header().clone();
+        self.upstream_request_filter(session, &mut req, ctx).await?;
+        grpc_web::text_upstream_request(&mut req, body.len(), upstream.as_http2().is_some())?;
+
+        upstream
+            .write_request_header(Box::new(req))
+            .await
+            .map_err(Error::into_up)?;
+        upstream
+            .write_request_body(body, true)
+            .await
+            .map_err(Error::into_up)?;
+        upstream
+            .finish_request_body()
+            .await
+            .map_err(Error::into_up)?;
+
+        let mut resp = loop {
+            upstream
+                .read_response_header()
+                .await
+                .map_err(Error::into_up)?;
+
+            if let Some(resp) = upstream
+                .response_header()
+                .filter(|x| !x.status.is_informational())
+            {
+                break resp.clone();
+            }
+        };
+
+        self.upstream_response_filter(session, &mut resp, ctx);
+        self.response_filter(session, &mut resp, ctx).await?;
+
+        // Like Pingora, which can't write HTTP/2 response headers to HTTP/1 clients
+        resp.set_version(http::Version::HTTP_11);
+        session
+            .write_response_header(Box::new(resp))
+            .await
+            .map_err(Error::into_down)?;
+
+        while let Some(data) = upstream
+            .read_response_body()
+            .await
+            .map_err(Error::into_up)?
+        {
+            ctx.upstream_bytes += data.len();
+            session
+                .write_response_body(grpc_web::encode_text(&data))
+                .await
+                .map_err(Error::into_down)?;
+        }
+
+        // gRPC responses only have trailers over HTTP/2
+        let trailers = match upstream {
+            UpstreamSession::H2(h2) => h2.read_trailers().await.map_err(Error::into_up)?,
+            UpstreamSession::H1(_) => None,
+        };
+
+        if let Some(trailers) = trailers {
+            let frame = grpc_web::trailer_frame(&trailers);
+            session
+                .write_response_body(grpc_web::encode_text(&frame))
+                .await
+                .map_err(Error::into_down)?;
+        }
+
+        session.finish_body().await.map_err(Error::into_down)
+    }
+
+    /// Respond to the client directly, still adding CORS headers
+    /// so browser apps can read the response.
+    ///
+    /// Returns whether the CORS headers were added.
+    async fn respond(
+        &self,
+        session: &mut Session,
+        info: &RequestInfo<'_>,
+        mut response: ResponseHeader,
+    ) -> bool {
+        let cors_applied = self
+            .add_response_headers(session, &mut response, info)
+            .unwrap_or_else(|e| {
+                warn!(?e, "Failed to add headers to response");
+                false
+            });
+
+        session.set_keepalive(None);
+
+        if let Err(e) = session.write_response_header(Box::new(response)).await {
+            warn!(?e, "Failed to send response to downstream");
+        }
+
+        cors_applied
     }
 }
 
+/// What is known about a request, for filling in header templates
+#[derive(Debug)]
+struct RequestInfo<'a> {
+    request_id: &'a str,
+    client_addr: Option<IpAddr>,
+    route: Option<usize>,
+}
+
 #[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct AddCorsHeadersCtx {
-    request_id: uuid::fmt::Simple,
-    request_start: std::time::Instant,
+    request_id: String,
+    request_start: Instant,
+    upstream_start: Option<Instant>,
+    upstream_duration: Option<Duration>,
+    upstream_addr: Option<SocketAddr>,
+    /// The address the connection the request came in on comes from
+    peer_addr: Option<IpAddr>,
+    /// The address of the client that made the request,
+    /// which is reported by the peer if it's a trusted proxy
+    client_addr: Option<IpAddr>,
+    /// The index of the matched route, or `None` for the default route
+    route: Option<usize>,
+    /// Where the request is forwarded to in open proxy mode
+    target: Option<Target>,
+    /// Concurrency limit slots held while the request is in flight
+    permits: Vec<OwnedSemaphorePermit>,
+    /// The encoding the response is compressed with, if it's compressible
+    encoding: Option<Encoding>,
+    /// How many times the upstream was tried
+    attempts: usize,
+    /// How many of the attempts were retries by the retry policy
+    retries: usize,
+    /// How long to wait before the next retry
+    backoff: Option<Duration>,
+    /// When the upstream must have responded by
+    deadline: Option<Instant>,
+    /// The permit of the circuit breaker that is waiting for the outcome of the request
+    circuit: Option<Permit>,
+    /// How the request is encoded, if it's translated from gRPC-Web to gRPC
+    grpc_web: Option<grpc_web::Encoding>,
+    /// Whether the request is a WebSocket handshake
+    websocket: bool,
+    /// Whether the response is streamed, guessed from the request until the response comes
+    stream: bool,
+    /// How many response body bytes were received from the upstream
+    upstream_bytes: usize,
+    cors_applied: bool,
     tracing_span: tracing::Span,
 }
 impl AddCorsHeadersCtx {
     fn new(config: &ProxyConfig) -> Self {
-        let t = config.proxy_to;
+        let t = config.proxy_to.addr();
         let m = field::Empty;
         let p = field::Empty;
-        let id = uuid::Uuid::now_v7().simple();
+        let id = field::Empty;
+        let r = field::Empty;
+        let att = field::Empty;
         let dur = field::Empty;
 
+        metrics::ACTIVE_REQUESTS.inc();
+
         Self {
-            request_id: id,
-            request_start: std::time::Instant::now(),
-            tracing_span: tracing::span!(tracing::Level::INFO, "req", %t, %id, m, p, dur),
+            request_id: uuid::Uuid::now_v7().simple().to_string(),
+            request_start: Instant::now(),
+            upstream_start: None,
+            upstream_duration: None,
+            upstream_addr: None,
+            peer_addr: None,
+            client_addr: None,
+            route: None,
+            target: None,
+            permits: Vec::new(),
+            encoding: None,
+            attempts: 0,
+            retries: 0,
+            backoff: None,
+            circuit: None,
+            deadline: None,
+            grpc_web: None,
+            websocket: false,
+            stream: false,
+            upstream_bytes: 0,
+            cors_applied: false,
+            tracing_span: tracing::span!(tracing::Level::INFO, "req", %t, id, m, p, r, att, dur),
+        }
+    }
+}
+impl AddCorsHeadersCtx {
+    fn info(&self) -> RequestInfo<'_> {
+        RequestInfo {
+            request_id: &self.request_id,
+            client_addr: self.client_addr,
+            route: self.route,
         }
     }
+
+    /// Whether an incoming request ID is safe to reuse
+    fn is_valid_request_id(id: &str) -> bool {
+        (1..=128).contains(&id.len())
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+    }
+}
+impl Drop for AddCorsHeadersCtx {
+    fn drop(&mut self) {
+        metrics::ACTIVE_REQUESTS.dec();
+    }
 }
 
+/// How many times a request may be sent to the upstream, the same as Pingora allows
+const MAX_ATTEMPTS: usize = 16;
+
 const HTTP_METHODS: &[Method] = &[
     Method::GET,
     Method::POST,
@@ -76,7 +1092,7 @@ const HTTP_METHODS: &[Method] = &[
     Method::TRACE,
 ];
 
-static HTTP_METHODS_STR: Lazy<String> = Lazy::new(|| {
+static HTTP_METHODS_STR: LazyLock<String> = LazyLock::new(|| {
     HTTP_METHODS
         .iter()
         .map(Method::as_str)
@@ -93,71 +1109,122 @@ impl ProxyHttp for AddCorsHeaders {
     }
 
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        ctx.tracing_span
+            .set_parent(telemetry::extract_context(&session.req_header().headers));
+
+        if let Some(id) = session
+            .get_header(self.config.request_id_header.as_str())
+            .and_then(|x| x.to_str().ok())
+            .filter(|x| Self::CTX::is_valid_request_id(x))
+        {
+            ctx.request_id = id.to_string();
+        }
+
         let _span = ctx.tracing_span.enter();
+
+        self.set_keepalive(session);
+
         let req_header = session.req_header();
 
+        ctx.peer_addr = connections::peer_addr().map(|x| x.ip());
+        ctx.client_addr = self.client_addr(session, ctx.peer_addr);
+
+        ctx.tracing_span
+            .record("id", field::display(&ctx.request_id));
         ctx.tracing_span
             .record("m", field::display(req_header.method.to_string()));
         ctx.tracing_span
             .record("p", field::display(req_header.uri.to_string()));
 
+        ctx.route = self.config.match_route(
+            req_header
+                .headers
+                .get(header::HOST)
+                .and_then(|x| x.to_str().ok()),
+            req_header.uri.path(),
+        );
+        ctx.tracing_span
+            .record("r", field::display(self.route_name(ctx.route)));
+
         info!("Incoming request");
 
-        let allowlist = &self.config.host_allowlist;
+        if self.client_rejected(ctx.client_addr) {
+            ctx.cors_applied = self
+                .respond(
+                    session,
+                    &ctx.info(),
+                    error_resp::gen_error_response(http::StatusCode::FORBIDDEN.as_u16()),
+                )
+                .await;
 
-        if allowlist.is_empty() {
-            trace!("Host allowlist is empty");
-            return Ok(false);
+            return Ok(true);
         }
 
-        let request_host = session
-            .get_header("Host")
-            .ok_or_else(|| {
-                pingora::Error::explain(ErrorType::UnknownError, "Missing Host header").into_down()
-            })
-            .and_then(|x| {
-                x.to_str().map_err(|e| {
-                    warn!(?e, "Failed to parse Host header");
+        if let Some(state) = self.rate_limited(session, ctx.client_addr) {
+            ctx.cors_applied = self.respond(session, &ctx.info(), state.response()?).await;
 
-                    pingora::Error::because(
-                        ErrorType::UnknownError,
-                        "Failed to parse Host header",
-                        e,
-                    )
-                    .into_in()
-                })
-            })
-            .map(str::to_lowercase);
+            return Ok(true);
+        }
 
-        let request_host = match request_host {
-            Ok(x) => x,
-            Err(e) => {
-                debug!(?e, "Failed to parse Host header");
+        ctx.grpc_web = self
+            .grpc_web(ctx.route)
+            .then(|| grpc_web::encoding(session.req_header()))
+            .flatten();
+        ctx.websocket = websocket::is_handshake(session);
+        ctx.stream = self.accepts_stream(session);
+
+        if let Some((status, reason)) = self.rejection(session, ctx.websocket) {
+            info!(?status, reason, "Request rejected");
+            metrics::REJECTIONS.with_label_values(&[reason]).inc();
+
+            // The body wasn't read, so the connection can't be reused
+            session.set_keepalive(None);
+
+            ctx.cors_applied = self
+                .respond(
+                    session,
+                    &ctx.info(),
+                    error_resp::gen_error_response(status.as_u16()),
+                )
+                .await;
 
-                session
-                    .respond_error(http::StatusCode::BAD_REQUEST.as_u16())
-                    .await;
+            return Ok(true);
+        }
 
-                return Ok(true);
+        if self.config.open_proxy.is_some() {
+            match Target::from_request(session.req_header()) {
+                Ok(target) => {
+                    debug!(?target, "Open proxy target");
+                    ctx.target = target;
+                }
+                Err(e) => {
+                    info!(e, "Invalid open proxy target");
+                    metrics::REJECTIONS
+                        .with_label_values(&["open_proxy_target"])
+                        .inc();
+
+                    ctx.cors_applied = self
+                        .respond(
+                            session,
+                            &ctx.info(),
+                            error_resp::gen_error_response(http::StatusCode::BAD_REQUEST.as_u16()),
+                        )
+                        .await;
+
+                    return Ok(true);
+                }
             }
-        };
-
-        debug!(host = ?request_host, "Got host header");
-
-        if !allowlist.contains(&request_host) {
-            debug!(
-                host = ?request_host,
-                ?allowlist,
-                "Host header not in allowlist"
-            );
+        }
 
-            info!(host = ?request_host, "Host header not in allowlist");
+        if self.rejected_by_host_allowlist(session).await {
+            return Ok(true);
+        }
 
-            session
-                .respond_error(http::StatusCode::BAD_REQUEST.as_u16())
-                .await;
+        ctx.encoding = self.enable_compression(session, ctx)?;
 
-            return Ok(true);
+        if ctx.grpc_web == Some(grpc_web::Encoding::Text) {
+            drop(_span);
+            return self.proxy_grpc_web_text(session, ctx).await.map(|()| true);
         }
 
         Ok(false)
@@ -165,27 +1232,101 @@ impl ProxyHttp for AddCorsHeaders {
 
     async fn upstream_peer(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
         let _span = ctx.tracing_span.enter();
 
-        let peer = {
-            let mut peer = if self.using_tls() {
-                HttpPeer::new(self.config.proxy_to, true, String::new())
-            } else {
-                HttpPeer::new(self.config.proxy_to, false, String::new())
+        // `upstream_peer` is called again when retrying, but the slots are already held by then
+        if ctx.permits.is_empty() {
+            self.acquire_upstream_slots(ctx.route, &mut ctx.permits)
+                .await?;
+        }
+
+        ctx.attempts += 1;
+        ctx.tracing_span.record("att", ctx.attempts);
+
+        if ctx.attempts == 1 {
+            if let Some(policy) = &self.retry_policy {
+                policy.deposit();
+            }
+
+            // WebSocket connections and streams stay open for as long as they're used
+            ctx.deadline = self
+                .upstream_timeouts(ctx.route)
+                .request
+                .filter(|_| !ctx.websocket && !ctx.stream)
+                .map(|x| ctx.request_start + x);
+        }
+
+        if let Some(backoff) = ctx.backoff.take() {
+            tokio::time::sleep(backoff).await;
+        }
+
+        if let (Some(target), Some(open_proxy)) = (&ctx.target, &self.config.open_proxy) {
+            let upstream_addr = match target.resolve(open_proxy).await {
+                Ok(x) => x,
+                Err(e) => {
+                    info!(e, "Open proxy destination not allowed");
+                    metrics::REJECTIONS
+                        .with_label_values(&["open_proxy_destination"])
+                        .inc();
+
+                    return Error::e_explain(
+                        HTTPStatus(http::StatusCode::FORBIDDEN.as_u16()),
+                        "Open proxy destination not allowed",
+                    );
+                }
             };
+            let upstream_addr =
+                self.pick_upstream_addr(&[upstream_addr], true, &mut ctx.circuit)?;
+            ctx.tracing_span.record("t", field::display(upstream_addr));
+
+            let mut peer = HttpPeer::new(
+                upstream_addr,
+                target.tls,
+                sni_for_host(&target.host_header()),
+            );
+            self.set_peer_protocol(&mut peer.options, self.upstream_protocol(None, ctx), target.tls);
+            self.set_peer_timeouts(&mut peer.options, ctx)?;
+
+            trace!(peer = ?peer, "Created open proxy peer");
 
-            peer.options.connection_timeout = Some(self.config.connection_timeout);
-            peer.options.total_connection_timeout = Some(self.config.total_connection_timeout);
-            peer.options.idle_timeout = self.config.idle_timeout;
+            ctx.upstream_addr = Some(upstream_addr);
+
+            return Ok(Box::new(peer));
+        }
+
+        let upstream_addrs = self.upstream_addrs.get(ctx.route).addrs(ctx.retries);
+        let upstream_addr = self.pick_upstream_addr(&upstream_addrs, false, &mut ctx.circuit)?;
+        ctx.tracing_span.record("t", field::display(upstream_addr));
+
+        let sni = self
+            .upstream_host_header(session, ctx.route)
+            .map(|x| sni_for_host(&x))
+            .unwrap_or_default();
+
+        let protocol = self.upstream_protocol(ctx.route, ctx);
+
+        let peer = {
+            // h2c is HTTP/2 without TLS
+            let tls = self.using_tls() && protocol != UpstreamProtocol::H2c;
+            let mut peer = HttpPeer::new(upstream_addr, tls, sni);
+
+            // Pingora only verifies certificates when there's a server name,
+            // and upstream certificates have never been verified
+            peer.options.verify_cert = false;
+
+            self.set_peer_protocol(&mut peer.options, protocol, tls);
+            self.set_peer_timeouts(&mut peer.options, ctx)?;
 
             peer
         };
 
         trace!(peer = ?peer, "Created peer");
 
+        ctx.upstream_addr = Some(upstream_addr);
+
         Ok(Box::new(peer))
     }
 
@@ -197,15 +1338,98 @@ impl ProxyHttp for AddCorsHeaders {
     ) -> Result<()> {
         let _span = ctx.tracing_span.enter();
 
-        if let Some(host) = session.get_header("Host") {
-            upstream_request.insert_header("Host", host)?;
+        let host = if let Some(target) = &ctx.target {
+            let uri = target
+                .path_and_query
+                .parse::<http::Uri>()
+                .or_err(InternalError, "Invalid open proxy target path")?;
+            upstream_request.set_uri(uri);
+
+            Some(target.host_header())
+        } else {
+            self.upstream_host_header(session, ctx.route)
+        };
+
+        self.rewrite_path(upstream_request, ctx.route)?;
+
+        if let Some(host) = host {
+            trace!(?host, "Setting upstream Host header");
+            upstream_request.insert_header(header::HOST, host)?;
+        }
+
+        upstream_request.insert_header(
+            self.config.request_id_header.clone(),
+            ctx.request_id.as_str(),
+        )?;
+
+        if ctx.target.is_some() {
+            // Open proxy destinations are third parties, which shouldn't learn about clients
+            // or the proxies in front of this one
+            forwarded::remove_headers(upstream_request);
+        } else {
+            forwarded::set_headers(
+                upstream_request,
+                self.config.forwarded_headers,
+                ctx.peer_addr,
+                forwarded::is_trusted(ctx.peer_addr, &self.config.trusted_proxies),
+                session
+                    .get_header(header::HOST)
+                    .and_then(|x| x.to_str().ok()),
+            )?;
+        }
+
+        for (name, value) in telemetry::inject_context(&ctx.tracing_span.context()) {
+            upstream_request.insert_header(name, value)?;
+        }
+
+        if let Some(encoding) = ctx.grpc_web {
+            grpc_web::upstream_request(upstream_request, encoding)?;
         }
 
+        header_rules::apply(
+            upstream_request,
+            self.header_rules(
+                ctx.route,
+                |x| &x.request_headers,
+                &self.config.request_headers,
+            ),
+            |var| self.template_value(session, &ctx.info(), var),
+        )?;
+
         trace!(headers = ?upstream_request.headers, "Modified upstream request");
 
+        ctx.upstream_start = Some(Instant::now());
+
         Ok(())
     }
 
+    fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        _upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        if let Some(upstream_start) = ctx.upstream_start {
+            let upstream_duration = upstream_start.elapsed();
+
+            metrics::UPSTREAM_LATENCY
+                .with_label_values(&[self.route_name(ctx.route)])
+                .observe(upstream_duration.as_secs_f64());
+
+            ctx.upstream_duration = Some(upstream_duration);
+        }
+    }
+
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) {
+        ctx.upstream_bytes += body.as_ref().map_or(0, Bytes::len);
+    }
+
     async fn response_filter(
         &self,
         session: &mut Session,
@@ -214,94 +1438,164 @@ impl ProxyHttp for AddCorsHeaders {
     ) -> Result<()> {
         let _span = ctx.tracing_span.enter();
 
-        upstream_response.append_header("X-CorsProxy-Request-Id", ctx.request_id.to_string())?;
+        // Only set for responses that came from the upstream, not the cache
+        if ctx.circuit.is_some() {
+            self.record_circuit(
+                &mut ctx.circuit,
+                ctx.target.is_some(),
+                !upstream_response.status.is_server_error(),
+            );
+        }
 
-        let mut vary_headers = vec![header::ORIGIN.to_string()];
+        if ctx.upstream_start.is_some() && ctx.deadline.is_some_and(|x| x <= Instant::now()) {
+            return deadline_exceeded();
+        }
 
-        let origin = session.get_header(header::ORIGIN);
-        let origin_str = origin.and_then(|x| x.to_str().ok()).map(str::to_lowercase);
+        // Responses served from the cache didn't come from the upstream just now
+        let status = upstream_response.status.as_u16();
+        if ctx.upstream_start.is_some() && upstream_response.status.is_server_error() {
+            if let Some(backoff) =
+                self.retry_backoff(session, ctx.retries, RetryCondition::Status(status))
+            {
+                ctx.retries += 1;
+                ctx.backoff = Some(backoff);
 
-        trace!(?origin, ?upstream_response, "Starting response filter");
+                let mut e = Error::new_up(HTTPStatus(status));
+                e.set_retry(true);
 
-        if !self.config.origin_allowlist.is_empty()
-            && !origin_str
-                .as_ref()
-                .is_some_and(|x| self.config.origin_allowlist.contains(x))
-        {
-            debug!(origin = ?origin, "Origin not in allowlist, not adding CORS headers");
+                return Err(e);
+            }
+        }
 
-            return Ok(());
+        if let Some(encoding) = ctx.grpc_web {
+            grpc_web::response(upstream_response, encoding, session.as_ref().is_http2())?;
         }
 
-        {
-            let upstream_headers = upstream_response
-                .headers
-                .iter()
-                .map(|x| x.0.to_string())
-                .collect::<Vec<String>>()
-                .join(", ");
+        ctx.stream = self.is_stream(upstream_response);
 
-            trace!(
-                headers = ?upstream_headers,
-                "Allowing all upstream headers",
-            );
+        ctx.cors_applied = self.add_response_headers(session, upstream_response, &ctx.info())?;
 
-            upstream_response
-                .insert_header(header::ACCESS_CONTROL_EXPOSE_HEADERS, upstream_headers)?;
+        if ctx.grpc_web.is_some() && ctx.cors_applied {
+            grpc_web::expose_status_headers(upstream_response)?;
         }
 
-        // upstream_response.insert_header(header::ACCESS_CONTROL_MAX_AGE, "1")?;
-
-        if let Some(origin) = origin_str {
-            debug!(origin = ?origin, "Adding origin-specific CORS headers");
-            upstream_response.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)?;
-            upstream_response.insert_header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
-        } else {
-            debug!("Adding generic CORS headers");
-            upstream_response.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")?;
+        if let Some(config) = &self.config.compression {
+            // Compressing would hold back parts of a stream until there's enough to compress
+            if !ctx.stream && compression::compressible(config, upstream_response) {
+                upstream_response.append_header(header::VARY, header::ACCEPT_ENCODING.as_str())?;
+            } else if ctx.encoding.take().is_some() {
+                session.downstream_compression.adjust_level(0);
+            }
         }
 
-        if let Some(header) = session.get_header(header::ACCESS_CONTROL_REQUEST_HEADERS) {
-            trace!(?header, "Adding Access-Control-Request-Headers header");
-            vary_headers.push(header::ACCESS_CONTROL_REQUEST_HEADERS.to_string());
-            upstream_response.insert_header(header::ACCESS_CONTROL_ALLOW_HEADERS, header)?;
-        } else {
-            upstream_response.insert_header(header::ACCESS_CONTROL_ALLOW_HEADERS, "*")?;
+        if self.config.decompression
+            && ctx.grpc_web.is_none()
+            && !ctx.websocket
+            && compression::decompressible(upstream_response)
+        {
+            upstream_response.append_header(header::VARY, header::ACCEPT_ENCODING.as_str())?;
         }
 
-        if let Some(header) = session.get_header(header::ACCESS_CONTROL_REQUEST_METHOD) {
-            trace!(?header, "Adding Access-Control-Request-Method header");
-            vary_headers.push(header::ACCESS_CONTROL_REQUEST_METHOD.to_string());
-            upstream_response.insert_header(header::ACCESS_CONTROL_ALLOW_METHODS, header)?;
-        } else {
-            upstream_response.insert_header(
-                header::ACCESS_CONTROL_ALLOW_METHODS,
-                HTTP_METHODS_STR.clone(),
-            )?;
+        if let Some(status) = self
+            .cache
+            .as_ref()
+            .and_then(|_| ResponseCache::status(&session.cache))
+        {
+            metrics::CACHE_RESPONSES.with_label_values(&[status]).inc();
+            upstream_response.insert_header("X-Cache", status)?;
         }
 
-        upstream_response.append_header(header::VARY, vary_headers.join(", "))?;
+        Ok(())
+    }
+
+    async fn response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        upstream_trailers: &mut HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Bytes>> {
+        // gRPC-Web clients get the trailers at the end of the body
+        Ok(ctx
+            .grpc_web
+            .is_some()
+            .then(|| grpc_web::trailer_frame(upstream_trailers)))
+    }
+
+    fn request_cache_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<()> {
+        if let Some(cache) = self.cache.as_ref().filter(|_| !ctx.websocket && !ctx.stream) {
+            cache.enable(&mut session.cache, session.downstream_session.req_header());
+        }
 
         Ok(())
     }
 
+    fn cache_key_callback(&self, session: &Session, ctx: &mut Self::CTX) -> Result<CacheKey> {
+        Ok(ResponseCache::key(
+            session.req_header(),
+            self.route_name(ctx.route),
+        ))
+    }
+
+    fn response_cache_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RespCacheable> {
+        if self.is_stream(resp) {
+            return Ok(RespCacheable::Uncacheable(NoCacheReason::Custom("stream")));
+        }
+
+        Ok(ResponseCache::response_cacheable(
+            session.req_header(),
+            resp,
+        ))
+    }
+
+    fn cache_vary_filter(
+        &self,
+        meta: &CacheMeta,
+        _ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Option<HashBinary> {
+        ResponseCache::variance(meta, req)
+    }
+
     fn fail_to_connect(
         &self,
-        _session: &mut Session,
-        _peer: &HttpPeer,
+        session: &mut Session,
+        peer: &HttpPeer,
         ctx: &mut Self::CTX,
         e: Box<Error>,
     ) -> Box<Error> {
-        let use_tls = self.using_tls();
+        let use_tls = peer.tls();
         debug!(?ctx, ?e, ?use_tls, "Failed to connect to upstream");
 
-        if use_tls && !self.set_use_tls(false) {
+        // The scheme of open proxy targets is explicit, so there's nothing to fall back to
+        if use_tls && ctx.target.is_none() && !self.set_use_tls(false) {
+            metrics::TLS_FALLBACKS.inc();
+
+            // The upstream may be fine, it just doesn't speak TLS
+            self.release_circuit(&mut ctx.circuit, ctx.target.is_some());
+
             let mut e = e.into_down();
             e.set_retry(true);
 
             return e;
         }
 
+        self.record_circuit(&mut ctx.circuit, ctx.target.is_some(), false);
+
+        if let Some(backoff) = self.retry_backoff(session, ctx.retries, RetryCondition::Connect) {
+            ctx.retries += 1;
+            ctx.backoff = Some(backoff);
+
+            let mut e = e;
+            e.set_retry(true);
+
+            return e;
+        }
+
         warn!(
             ctx = ?ctx,
             ?e,
@@ -311,12 +1605,126 @@ impl ProxyHttp for AddCorsHeaders {
         e
     }
 
-    async fn logging(&self, _session: &mut Session, err: Option<&Error>, ctx: &mut Self::CTX) {
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        let mut e = e.more_context(format!("Peer: {peer}"));
+        // Only retry errors on reused connections if the request body can be sent again
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+
+        if e.esource() == &ErrorSource::Upstream {
+            self.record_circuit(&mut ctx.circuit, ctx.target.is_some(), false);
+        }
+
+        // Errors that stem from the response filter, eg. retried statuses, are already decided
+        if !e.retry() && e.esource() == &ErrorSource::Upstream {
+            if let Some(backoff) = self.retry_backoff(session, ctx.retries, RetryCondition::Reset)
+            {
+                ctx.retries += 1;
+                ctx.backoff = Some(backoff);
+                e.set_retry(true);
+            }
+        }
+
+        e
+    }
+
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16 {
+        let _span = ctx.tracing_span.enter();
+
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => match e.etype() {
+                    ConnectTimedout | ReadTimedout | WriteTimedout => 504,
+                    _ => 502,
+                },
+                ErrorSource::Downstream => match e.etype() {
+                    // The connection is already gone
+                    WriteError | ReadError | ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+
+        if code > 0 && session.response_written().is_none() {
+            ctx.cors_applied = self
+                .respond(session, &ctx.info(), error_resp::gen_error_response(code))
+                .await;
+        }
+
+        code
+    }
+
+    async fn logging(&self, session: &mut Session, err: Option<&Error>, ctx: &mut Self::CTX) {
         let _span = ctx.tracing_span.enter();
 
+        let dur = Instant::now().duration_since(ctx.request_start);
+        ctx.tracing_span.record("dur", field::debug(dur));
+
+        // The request ended before the upstream answered, eg. because the client went away
+        self.release_circuit(&mut ctx.circuit, ctx.target.is_some());
+
+        let status = session.response_written().map_or(0, |x| x.status.as_u16());
+
         {
-            let dur = std::time::Instant::now().duration_since(ctx.request_start);
-            ctx.tracing_span.record("dur", field::debug(dur));
+            let method = session.req_header().method.as_str();
+            let status = status.to_string();
+            let labels = [method, status.as_str(), self.route_name(ctx.route)];
+
+            metrics::REQUESTS.with_label_values(&labels).inc();
+            metrics::REQUEST_DURATION
+                .with_label_values(&labels)
+                .observe(dur.as_secs_f64());
+        }
+
+        if let Some(access_log) = &self.access_log {
+            let req_header = session.req_header();
+            let header_str = |name| session.get_header(name).and_then(|x| x.to_str().ok());
+
+            access_log.log(&AccessLogEntry {
+                time: chrono::Utc::now(),
+                request_id: &ctx.request_id,
+                client: ctx.client_addr,
+                method: req_header.method.as_str(),
+                path: &req_header.uri.to_string(),
+                protocol: &format!("{:?}", req_header.version),
+                host: header_str(header::HOST),
+                origin: header_str(header::ORIGIN),
+                referer: header_str(header::REFERER),
+                user_agent: header_str(header::USER_AGENT),
+                status,
+                bytes_in: header_str(header::CONTENT_LENGTH).and_then(|x| x.parse().ok()),
+                bytes_out: session.body_bytes_sent(),
+                upstream: ctx.upstream_addr,
+                upstream_time: ctx.upstream_duration,
+                duration: dur,
+                cors: ctx.cors_applied,
+            });
+        }
+
+        if ctx.websocket && status == http::StatusCode::SWITCHING_PROTOCOLS.as_u16() {
+            // Pingora doesn't count what the client sent after the handshake
+            info!(
+                duration = ?dur,
+                bytes_out = ctx.upstream_bytes,
+                "WebSocket connection closed"
+            );
+        }
+
+        if ctx.stream {
+            info!(
+                duration = ?dur,
+                bytes_out = ctx.upstream_bytes,
+                "Stream closed"
+            );
         }
 
         if let Some(err) = err {
@@ -330,3 +1738,58 @@ impl ProxyHttp for AddCorsHeaders {
         true
     }
 }
+
+fn deadline_exceeded<T>() -> Result<T> {
+    info!("Upstream request deadline exceeded");
+
+    Error::e_explain(
+        HTTPStatus(http::StatusCode::GATEWAY_TIMEOUT.as_u16()),
+        "Upstream request deadline exceeded",
+    )
+}
+
+/// The TLS server name for a `Host` header.
+///
+/// Empty if the host is an IP address, since those can't be sent as a server name.
+pub fn sni_for_host(host: &str) -> String {
+    // IPv6 addresses are in brackets, eg. `[2001:db8::1]:8080`
+    let name = host
+        .strip_prefix('[')
+        .and_then(|x| x.split_once(']'))
+        .map_or_else(|| host.split(':').next().unwrap_or(host), |(name, _)| name);
+
+    if name.parse::<IpAddr>().is_ok() {
+        return String::new();
+    }
+
+    name.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_are_reused_if_valid() {
+        for id in [
+            "a",
+            "0af7651916cd43dd8448eb211c80319c",
+            "123e4567-e89b-12d3-a456-426614174000",
+            "1-5759e988-bd862e3fe1be46a994272793:2",
+            "req_1.2",
+        ] {
+            assert!(AddCorsHeadersCtx::is_valid_request_id(id), "{id}");
+        }
+
+        assert!(AddCorsHeadersCtx::is_valid_request_id(&"a".repeat(128)));
+    }
+
+    #[test]
+    fn request_ids_are_replaced_if_invalid() {
+        for id in ["", "a b", "a\"b", "a;b", "a=b", "a/b", "a\\b", "é", "a\tb"] {
+            assert!(!AddCorsHeadersCtx::is_valid_request_id(id), "{id:?}");
+        }
+
+        assert!(!AddCorsHeadersCtx::is_valid_request_id(&"a".repeat(129)));
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::{LazyLock, OnceLock};
+
+use http::{header, HeaderMap, Method};
+use pingora::{
+    cache::{
+        cache_control::CacheControl, eviction::simple_lru, filters::resp_cacheable,
+        key::HashBinary, lock::CacheLock, CacheKey, CacheMeta, CacheMetaDefaults, CachePhase,
+        HttpCache, NoCacheReason, RespCacheable, VarianceBuilder,
+    },
+    http::{RequestHeader, ResponseHeader},
+};
+
+use self::storage::MemoryStorage;
+use crate::config::common::cache::CacheConfig;
+
+mod storage;
+
+/// Responses without `Cache-Control` or `Expires` aren't cached
+const DEFAULTS: CacheMetaDefaults = CacheMetaDefaults::new(|_| None, 0, 0);
+
+// Pingora requires these to live for the rest of the program, so there's one cache per process
+static STORAGE: LazyLock<MemoryStorage> = LazyLock::new(MemoryStorage::default);
+static EVICTION: OnceLock<simple_lru::Manager> = OnceLock::new();
+static LOCK: OnceLock<CacheLock> = OnceLock::new();
+
+/// The in-memory response cache
+pub struct ResponseCache {
+    config: CacheConfig,
+    eviction: &'static simple_lru::Manager,
+    /// Makes concurrent requests for the same response wait for the first one to fetch it
+    lock: Option<&'static CacheLock>,
+}
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        let eviction = EVICTION.get_or_init(|| simple_lru::Manager::new(config.size));
+        let lock = config
+            .lock_timeout
+            .map(|timeout| LOCK.get_or_init(|| CacheLock::new(timeout)));
+
+        Self {
+            config,
+            eviction,
+            lock,
+        }
+    }
+
+    /// Look up the request in the cache if it can be cached
+    pub fn enable(&self, cache: &mut HttpCache, req: &RequestHeader) {
+        if req.method != Method::GET {
+            return;
+        }
+
+        cache.enable(&*STORAGE, Some(self.eviction), None, self.lock);
+        cache.set_max_file_size_bytes(self.config.max_response_size);
+    }
+
+    /// The cache key of a request.
+    ///
+    /// CORS headers are added per request, but the upstream may still answer differently
+    /// depending on the `Origin`, so it's part of the key.
+    pub fn key(req: &RequestHeader, route: &str) -> CacheKey {
+        let header = |name| {
+            req.headers
+                .get(name)
+                .map(|x| String::from_utf8_lossy(x.as_bytes()).into_owned())
+                .unwrap_or_default()
+        };
+
+        let host = req
+            .uri
+            .host()
+            .map_or_else(|| header(header::HOST), String::from);
+
+        CacheKey::new(
+            route,
+            format!("{} {host}{}", header(header::ORIGIN), req.uri),
+            "",
+        )
+    }
+
+    /// Whether an upstream response can be cached, and for how long
+    pub fn response_cacheable(req: &RequestHeader, resp: &ResponseHeader) -> RespCacheable {
+        if resp.headers.contains_key(header::SET_COOKIE) {
+            return RespCacheable::Uncacheable(NoCacheReason::Custom("set-cookie"));
+        }
+
+        if vary_names(&resp.headers).iter().any(|x| x == "*") {
+            return RespCacheable::Uncacheable(NoCacheReason::Custom("vary"));
+        }
+
+        let cache_control = CacheControl::from_resp_headers(resp);
+
+        resp_cacheable(
+            cache_control.as_ref(),
+            resp,
+            req.headers.contains_key(header::AUTHORIZATION),
+            &DEFAULTS,
+        )
+    }
+
+    /// The values of the request headers named in the `Vary` header of a cached response
+    pub fn variance(meta: &CacheMeta, req: &RequestHeader) -> Option<HashBinary> {
+        let names = vary_names(meta.headers());
+
+        let mut variance = VarianceBuilder::new();
+        for name in &names {
+            let value = req
+                .headers
+                .get_all(name.as_str())
+                .iter()
+                .flat_map(|x| [x.as_bytes(), b","])
+                .flatten()
+                .copied()
+                .collect();
+
+            variance.add_owned_value(name, value);
+        }
+
+        variance.finalize()
+    }
+
+    /// The value of the `X-Cache` header for a response
+    pub fn status(cache: &HttpCache) -> Option<&'static str> {
+        match cache.phase() {
+            CachePhase::Hit => Some("HIT"),
+            CachePhase::Stale => Some("STALE"),
+            CachePhase::Revalidated | CachePhase::RevalidatedNoCache(_) => Some("REVALIDATED"),
+            CachePhase::Disabled(NoCacheReason::NeverEnabled) | CachePhase::Bypass => {
+                Some("BYPASS")
+            }
+            // Otherwise disabled means it was looked up, but the response turned out not to be cacheable
+            CachePhase::Miss | CachePhase::Expired | CachePhase::Disabled(_) => Some("MISS"),
+            CachePhase::Uninit | CachePhase::CacheKey => None,
+        }
+    }
+}
+impl std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The lowercase header names in the `Vary` header
+fn vary_names(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
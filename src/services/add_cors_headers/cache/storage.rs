@@ -0,0 +1,361 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use pingora::{
+    cache::{
+        key::{CacheHashKey, CompactCacheKey, HashBinary},
+        storage::{HandleHit, HandleMiss},
+        trace::SpanHandle,
+        CacheKey, CacheMeta, HitHandler, MissHandler, Storage,
+    },
+    Error, ErrorType, Result,
+};
+
+/// Cached responses kept in memory.
+///
+/// Pingora's `MemCache` is only meant for tests, so this is a minimal storage of our own.
+/// Responses only become visible once their whole body is written, and the eviction manager
+/// keeps their total size within the limit by purging the least recently used ones.
+#[derive(Default)]
+pub struct MemoryStorage {
+    entries: RwLock<HashMap<HashBinary, Arc<Entry>>>,
+}
+
+struct Entry {
+    /// The serialized `CacheMeta`
+    meta: (Vec<u8>, Vec<u8>),
+    body: Bytes,
+}
+
+impl MemoryStorage {
+    fn get(&self, key: &impl CacheHashKey) -> Option<Arc<Entry>> {
+        self.entries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key.combined_bin())
+            .cloned()
+    }
+
+    fn insert(&self, key: HashBinary, entry: Entry) {
+        self.entries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, Arc::new(entry));
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        let Some(entry) = self.get(key) else {
+            return Ok(None);
+        };
+
+        let meta = CacheMeta::deserialize(&entry.meta.0, &entry.meta.1)?;
+        let hit = Hit {
+            body: entry.body.clone(),
+            start: 0,
+            end: entry.body.len(),
+            done: false,
+        };
+
+        Ok(Some((meta, Box::new(hit))))
+    }
+
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        Ok(Box::new(Miss {
+            storage: self,
+            key: key.combined_bin(),
+            meta: meta.serialize()?,
+            body: BytesMut::new(),
+        }))
+    }
+
+    async fn purge(&'static self, key: &CompactCacheKey, _trace: &SpanHandle) -> Result<bool> {
+        Ok(self
+            .entries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&key.combined_bin())
+            .is_some())
+    }
+
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        // The response may have been evicted while it was being revalidated
+        let Some(entry) = self.get(key) else {
+            return Ok(false);
+        };
+
+        self.insert(
+            key.combined_bin(),
+            Entry {
+                meta: meta.serialize()?,
+                body: entry.body.clone(),
+            },
+        );
+
+        Ok(true)
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync + 'static) {
+        self
+    }
+}
+
+/// Reads a cached body, or a range of it
+struct Hit {
+    body: Bytes,
+    start: usize,
+    end: usize,
+    done: bool,
+}
+
+#[async_trait]
+impl HandleHit for Hit {
+    async fn read_body(&mut self) -> Result<Option<Bytes>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+
+        Ok(Some(self.body.slice(self.start..self.end)))
+    }
+
+    async fn finish(
+        self: Box<Self>,
+        _storage: &'static (dyn Storage + Sync),
+        _key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    fn seek(&mut self, start: usize, end: Option<usize>) -> Result<()> {
+        if start >= self.body.len() {
+            return Error::e_explain(
+                ErrorType::InternalError,
+                format!(
+                    "Seek start {start} is past the body length {}",
+                    self.body.len()
+                ),
+            );
+        }
+
+        self.start = start;
+        self.end = end.map_or(self.body.len(), |x| x.clamp(start, self.body.len()));
+        self.done = false;
+
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// Buffers a response body and stores it once it's complete.
+///
+/// Responses that aren't finished, eg. because the upstream failed, are dropped.
+struct Miss {
+    storage: &'static MemoryStorage,
+    key: HashBinary,
+    meta: (Vec<u8>, Vec<u8>),
+    body: BytesMut,
+}
+
+#[async_trait]
+impl HandleMiss for Miss {
+    async fn write_body(&mut self, data: Bytes, _eof: bool) -> Result<()> {
+        self.body.extend_from_slice(&data);
+
+        Ok(())
+    }
+
+    async fn finish(self: Box<Self>) -> Result<usize> {
+        let size = self.meta.0.len() + self.meta.1.len() + self.body.len();
+
+        self.storage.insert(
+            self.key,
+            Entry {
+                meta: self.meta,
+                body: self.body.freeze(),
+            },
+        );
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::LazyLock,
+        time::{Duration, SystemTime},
+    };
+
+    use pingora::{cache::trace::Span, http::ResponseHeader};
+
+    use super::*;
+
+    fn meta(status: u16) -> CacheMeta {
+        let now = SystemTime::now();
+
+        CacheMeta::new(
+            now + Duration::from_secs(60),
+            now,
+            0,
+            0,
+            ResponseHeader::build(status, None).expect("valid response"),
+        )
+    }
+
+    async fn store(storage: &'static MemoryStorage, key: &CacheKey, chunks: &[&'static str]) {
+        let trace = Span::inactive().handle();
+        let mut miss = storage
+            .get_miss_handler(key, &meta(200), &trace)
+            .await
+            .expect("miss handler");
+
+        for chunk in chunks {
+            miss.write_body(Bytes::from_static(chunk.as_bytes()), false)
+                .await
+                .expect("body written");
+        }
+        miss.finish().await.expect("body stored");
+    }
+
+    async fn read(storage: &'static MemoryStorage, key: &CacheKey) -> Option<(u16, String)> {
+        let (meta, mut hit) = storage
+            .lookup(key, &Span::inactive().handle())
+            .await
+            .expect("lookup")?;
+
+        let mut body = String::new();
+        while let Some(chunk) = hit.read_body().await.expect("body read") {
+            body.push_str(std::str::from_utf8(&chunk).expect("UTF-8 body"));
+        }
+
+        Some((meta.response_header().status.as_u16(), body))
+    }
+
+    #[tokio::test]
+    async fn stores_finished_responses() {
+        static STORAGE: LazyLock<MemoryStorage> = LazyLock::new(MemoryStorage::default);
+        let key = CacheKey::new("", "a", "");
+
+        assert_eq!(read(&STORAGE, &key).await, None);
+
+        store(&STORAGE, &key, &["hello ", "world"]).await;
+        assert_eq!(
+            read(&STORAGE, &key).await,
+            Some((200, "hello world".to_string()))
+        );
+        assert_eq!(read(&STORAGE, &CacheKey::new("", "b", "")).await, None);
+    }
+
+    #[tokio::test]
+    async fn unfinished_responses_are_dropped() {
+        static STORAGE: LazyLock<MemoryStorage> = LazyLock::new(MemoryStorage::default);
+        let key = CacheKey::new("", "a", "");
+
+        let mut miss = STORAGE
+            .get_miss_handler(&key, &meta(200), &Span::inactive().handle())
+            .await
+            .expect("miss handler");
+        miss.write_body(Bytes::from_static(b"partial"), false)
+            .await
+            .expect("body written");
+        drop(miss);
+
+        assert_eq!(read(&STORAGE, &key).await, None);
+    }
+
+    #[tokio::test]
+    async fn purges_responses() {
+        static STORAGE: LazyLock<MemoryStorage> = LazyLock::new(MemoryStorage::default);
+        let key = CacheKey::new("", "a", "");
+        let trace = Span::inactive().handle();
+
+        store(&STORAGE, &key, &["x"]).await;
+
+        assert!(STORAGE
+            .purge(&key.to_compact(), &trace)
+            .await
+            .expect("purge"));
+        assert_eq!(read(&STORAGE, &key).await, None);
+        assert!(!STORAGE
+            .purge(&key.to_compact(), &trace)
+            .await
+            .expect("purge"));
+    }
+
+    #[tokio::test]
+    async fn updates_meta_of_stored_responses_only() {
+        static STORAGE: LazyLock<MemoryStorage> = LazyLock::new(MemoryStorage::default);
+        let key = CacheKey::new("", "a", "");
+        let trace = Span::inactive().handle();
+
+        assert!(!STORAGE
+            .update_meta(&key, &meta(203), &trace)
+            .await
+            .expect("update"));
+
+        store(&STORAGE, &key, &["x"]).await;
+        assert!(STORAGE
+            .update_meta(&key, &meta(203), &trace)
+            .await
+            .expect("update"));
+        assert_eq!(read(&STORAGE, &key).await, Some((203, "x".to_string())));
+    }
+
+    #[tokio::test]
+    async fn seeks_to_ranges() {
+        static STORAGE: LazyLock<MemoryStorage> = LazyLock::new(MemoryStorage::default);
+        let key = CacheKey::new("", "a", "");
+
+        store(&STORAGE, &key, &["0123456789"]).await;
+        let (_, mut hit) = STORAGE
+            .lookup(&key, &Span::inactive().handle())
+            .await
+            .expect("lookup")
+            .expect("stored");
+
+        hit.seek(2, Some(5)).expect("in range");
+        assert_eq!(
+            hit.read_body().await.expect("body read").as_deref(),
+            Some(&b"234"[..])
+        );
+        assert_eq!(hit.read_body().await.expect("body read"), None);
+
+        hit.seek(7, Some(100)).expect("in range");
+        assert_eq!(
+            hit.read_body().await.expect("body read").as_deref(),
+            Some(&b"789"[..])
+        );
+
+        assert!(hit.seek(10, None).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+use tracing::{info, warn};
+
+use crate::{config::common::circuit_breaker::CircuitBreakerConfig, metrics};
+
+/// Don't bother cleaning up circuits until there are at least this many
+const MIN_CLEANUP_SIZE: usize = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Requests go through, and failures are counted
+    Closed,
+    /// Requests are rejected until the open duration has passed
+    Open,
+    /// Trial requests go through one at a time to check if the upstream has recovered
+    HalfOpen,
+}
+impl State {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half_open",
+        }
+    }
+
+    const fn as_gauge(self) -> i64 {
+        match self {
+            Self::Closed => 0,
+            Self::Open => 1,
+            Self::HalfOpen => 2,
+        }
+    }
+}
+
+/// The circuit of one upstream address
+#[derive(Debug)]
+struct Circuit {
+    state: State,
+    /// When the circuit entered its current state
+    since: Instant,
+    consecutive_failures: u32,
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+    /// Whether a trial request is in flight while half-open
+    trial_in_flight: bool,
+    trial_successes: u32,
+}
+impl Circuit {
+    fn new() -> Self {
+        let now = Instant::now();
+
+        Self {
+            state: State::Closed,
+            since: now,
+            consecutive_failures: 0,
+            window_start: now,
+            window_requests: 0,
+            window_failures: 0,
+            trial_in_flight: false,
+            trial_successes: 0,
+        }
+    }
+
+    /// Whether the circuit hasn't seen a request for long enough that it can be forgotten
+    fn idle(&self, config: &CircuitBreakerConfig) -> bool {
+        match self.state {
+            // A request after the window ends starts a new one, so there was none for a window
+            State::Closed => self.window_start.elapsed() >= config.window * 2,
+            State::Open => self.since.elapsed() >= config.open_duration + config.window,
+            State::HalfOpen => !self.trial_in_flight && self.since.elapsed() >= config.window,
+        }
+    }
+
+    fn allow(
+        &mut self,
+        addr: SocketAddr,
+        label: Label,
+        config: &CircuitBreakerConfig,
+    ) -> Option<Permit> {
+        let trial = match self.state {
+            State::Closed => false,
+            State::Open if self.since.elapsed() >= config.open_duration => {
+                self.transition(addr, label, State::HalfOpen);
+                true
+            }
+            State::Open => return None,
+            State::HalfOpen if self.trial_in_flight => return None,
+            State::HalfOpen => true,
+        };
+
+        self.trial_in_flight |= trial;
+
+        Some(Permit { addr, trial })
+    }
+
+    fn record(
+        &mut self,
+        permit: Permit,
+        label: Label,
+        success: bool,
+        config: &CircuitBreakerConfig,
+    ) {
+        let addr = permit.addr;
+
+        match self.state {
+            State::Closed => {
+                if self.window_start.elapsed() >= config.window {
+                    self.window_start = Instant::now();
+                    self.window_requests = 0;
+                    self.window_failures = 0;
+                }
+
+                self.window_requests += 1;
+
+                if success {
+                    self.consecutive_failures = 0;
+                    return;
+                }
+
+                self.window_failures += 1;
+                self.consecutive_failures += 1;
+
+                let error_rate = f64::from(self.window_failures) / f64::from(self.window_requests);
+
+                if self.consecutive_failures >= config.failures
+                    || (self.window_requests >= config.min_requests
+                        && error_rate >= config.error_rate)
+                {
+                    self.transition(addr, label, State::Open);
+                }
+            }
+            // Requests that were let through before the circuit opened aren't trials
+            State::HalfOpen if !permit.trial => {}
+            State::HalfOpen => {
+                self.trial_in_flight = false;
+
+                if !success {
+                    self.transition(addr, label, State::Open);
+                    return;
+                }
+
+                self.trial_successes += 1;
+                if self.trial_successes >= config.trial_requests {
+                    self.transition(addr, label, State::Closed);
+                }
+            }
+            // Requests that were in flight when the circuit opened
+            State::Open => {}
+        }
+    }
+
+    fn transition(&mut self, addr: SocketAddr, label: Label, state: State) {
+        let from = self.state;
+
+        *self = Self {
+            state,
+            ..Self::new()
+        };
+
+        if state == State::Open {
+            warn!(%addr, from = from.as_str(), "Circuit opened, rejecting requests to upstream");
+        } else {
+            info!(%addr, from = from.as_str(), to = state.as_str(), "Circuit state changed");
+        }
+
+        match label {
+            Label::Addr => {
+                let upstream = addr.to_string();
+                metrics::CIRCUIT_BREAKER_TRANSITIONS
+                    .with_label_values(&[&upstream, state.as_str()])
+                    .inc();
+                metrics::CIRCUIT_BREAKER_STATE
+                    .with_label_values(&[&upstream])
+                    .set(state.as_gauge());
+            }
+            Label::Fixed(upstream) => {
+                metrics::CIRCUIT_BREAKER_TRANSITIONS
+                    .with_label_values(&[upstream, state.as_str()])
+                    .inc();
+            }
+        }
+    }
+}
+
+/// A request the circuit breaker let through, to hand back with its outcome
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permit {
+    pub addr: SocketAddr,
+    /// Whether the request is the trial of a half-open circuit
+    trial: bool,
+}
+
+/// How circuits are labelled in metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Label {
+    /// By upstream address, with a state gauge for each
+    Addr,
+    /// All with the same label and without state gauges,
+    /// for upstreams that aren't known in advance, like open proxy destinations
+    Fixed(&'static str),
+}
+
+/// Circuit breakers for each upstream address
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    label: Label,
+    circuits: Mutex<Circuits>,
+}
+
+#[derive(Debug)]
+struct Circuits {
+    circuits: HashMap<SocketAddr, Circuit>,
+    next_cleanup: usize,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig, label: Label) -> Self {
+        Self {
+            config,
+            label,
+            circuits: Mutex::new(Circuits {
+                circuits: HashMap::new(),
+                next_cleanup: MIN_CLEANUP_SIZE,
+            }),
+        }
+    }
+
+    /// A permit to send a request to the address, or `None` if its circuit is open.
+    ///
+    /// Every permit must be handed back to [`Self::record`] or [`Self::release`].
+    pub fn allow(&self, addr: SocketAddr) -> Option<Permit> {
+        self.circuits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .circuits
+            .get_mut(&addr)
+            .map_or(Some(Permit { addr, trial: false }), |circuit| {
+                circuit.allow(addr, self.label, &self.config)
+            })
+    }
+
+    /// Record the outcome of a request that was allowed
+    pub fn record(&self, permit: Permit, success: bool) {
+        let addr = permit.addr;
+        let mut circuits = self.circuits.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if !circuits.circuits.contains_key(&addr)
+            && circuits.circuits.len() >= circuits.next_cleanup
+        {
+            let label = self.label;
+            circuits.circuits.retain(|addr, circuit| {
+                let idle = circuit.idle(&self.config);
+                if idle && label == Label::Addr {
+                    let _ =
+                        metrics::CIRCUIT_BREAKER_STATE.remove_label_values(&[&addr.to_string()]);
+                }
+                !idle
+            });
+            circuits.next_cleanup = (circuits.circuits.len() * 2).max(MIN_CLEANUP_SIZE);
+        }
+
+        circuits
+            .circuits
+            .entry(addr)
+            .or_insert_with(Circuit::new)
+            .record(permit, self.label, success, &self.config);
+    }
+
+    /// Give up a request that was allowed without an outcome, eg. because the client went away
+    pub fn release(&self, permit: Permit) {
+        if !permit.trial {
+            return;
+        }
+
+        if let Some(circuit) = self
+            .circuits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .circuits
+            .get_mut(&permit.addr)
+        {
+            circuit.trial_in_flight = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    fn circuit_breaker(open_duration: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(
+            CircuitBreakerConfig {
+                failures: 1,
+                error_rate: 1.0,
+                min_requests: 1,
+                window: Duration::ZERO,
+                open_duration,
+                trial_requests: 1,
+            },
+            Label::Fixed("test"),
+        )
+    }
+
+    fn addr(i: usize) -> SocketAddr {
+        let i = u32::try_from(i).expect("small index");
+        SocketAddr::from((Ipv4Addr::from(i), 80))
+    }
+
+    /// Send a request through the circuit breaker
+    fn request(circuit_breaker: &CircuitBreaker, addr: SocketAddr, success: bool) {
+        let permit = circuit_breaker
+            .allow(addr)
+            .expect("circuit allows requests");
+        circuit_breaker.record(permit, success);
+    }
+
+    fn len(circuit_breaker: &CircuitBreaker) -> usize {
+        circuit_breaker
+            .circuits
+            .lock()
+            .expect("not poisoned")
+            .circuits
+            .len()
+    }
+
+    #[test]
+    fn prunes_idle_circuits() {
+        let circuit_breaker = circuit_breaker(Duration::ZERO);
+
+        for i in 0..MIN_CLEANUP_SIZE {
+            request(&circuit_breaker, addr(i), i % 2 == 0);
+        }
+        assert_eq!(len(&circuit_breaker), MIN_CLEANUP_SIZE);
+
+        request(&circuit_breaker, addr(MIN_CLEANUP_SIZE), true);
+        assert_eq!(len(&circuit_breaker), 1);
+    }
+
+    #[test]
+    fn keeps_open_circuits() {
+        let circuit_breaker = circuit_breaker(Duration::MAX);
+
+        for i in 0..MIN_CLEANUP_SIZE {
+            request(&circuit_breaker, addr(i), false);
+        }
+
+        request(&circuit_breaker, addr(MIN_CLEANUP_SIZE), true);
+        assert_eq!(len(&circuit_breaker), MIN_CLEANUP_SIZE + 1);
+        assert_eq!(circuit_breaker.allow(addr(0)), None);
+        assert!(circuit_breaker.allow(addr(MIN_CLEANUP_SIZE)).is_some());
+    }
+
+    #[test]
+    fn trial_decides_whether_the_circuit_closes() {
+        let circuit_breaker = circuit_breaker(Duration::ZERO);
+
+        request(&circuit_breaker, addr(0), false);
+
+        let trial = circuit_breaker
+            .allow(addr(0))
+            .expect("circuit is half-open");
+        assert_eq!(circuit_breaker.allow(addr(0)), None);
+
+        circuit_breaker.record(trial, false);
+        let trial = circuit_breaker
+            .allow(addr(0))
+            .expect("circuit is half-open");
+
+        circuit_breaker.release(trial);
+        let trial = circuit_breaker
+            .allow(addr(0))
+            .expect("circuit is half-open");
+
+        circuit_breaker.record(trial, true);
+        let first = circuit_breaker.allow(addr(0)).expect("circuit is closed");
+        assert!(circuit_breaker.allow(addr(0)).is_some());
+        circuit_breaker.release(first);
+    }
+
+    #[test]
+    fn requests_from_before_the_trial_are_not_trials() {
+        let circuit_breaker = circuit_breaker(Duration::ZERO);
+
+        let recorded = circuit_breaker.allow(addr(0)).expect("circuit is closed");
+        let released = circuit_breaker.allow(addr(0)).expect("circuit is closed");
+        request(&circuit_breaker, addr(0), false);
+
+        let trial = circuit_breaker
+            .allow(addr(0))
+            .expect("circuit is half-open");
+
+        // Neither frees up the trial, nor decides how it went
+        circuit_breaker.record(recorded, true);
+        circuit_breaker.release(released);
+        assert_eq!(circuit_breaker.allow(addr(0)), None);
+
+        circuit_breaker.record(trial, false);
+        assert!(circuit_breaker.allow(addr(0)).is_some_and(|x| x.trial));
+    }
+}
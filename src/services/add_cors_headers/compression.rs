@@ -0,0 +1,190 @@
+use http::{header, HeaderValue, Method};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    prelude::*,
+};
+
+use crate::config::common::compression::{CompressionConfig, Encoding};
+
+/// The most preferred configured encoding the client accepts
+fn negotiate(
+    config: &CompressionConfig,
+    accept_encoding: Option<&HeaderValue>,
+) -> Option<Encoding> {
+    let accepted = accept_encoding
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|x| {
+            let mut params = x.split(';');
+            let coding = params.next()?.trim();
+
+            // `q=0` means the client doesn't accept the coding
+            let refused = params.any(|x| {
+                x.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+
+            (!refused).then_some(coding)
+        })
+        .collect::<Vec<_>>();
+
+    config
+        .algorithms
+        .iter()
+        .copied()
+        .find(|x| accepted.iter().any(|a| a.eq_ignore_ascii_case(x.as_str())))
+}
+
+/// Set up Pingora's response compression for the request
+pub fn enable(config: &CompressionConfig, session: &mut Session) -> Result<Option<Encoding>> {
+    let encoding = negotiate(config, session.get_header(header::ACCEPT_ENCODING));
+
+    if let Some(encoding) = encoding {
+        let compression = &mut session.downstream_compression;
+        compression.adjust_level(config.level(encoding));
+
+        // Pingora compresses with the first encoding it's told the client accepts,
+        // and is told the actual `Accept-Encoding` after this, so the preferred one goes first
+        let mut preferred = RequestHeader::build(Method::GET, b"/", None)?;
+        preferred.insert_header(header::ACCEPT_ENCODING, encoding.as_str())?;
+        compression.request_filter(&preferred);
+    }
+
+    Ok(encoding)
+}
+
+/// Whether Pingora can decompress the response for clients that don't accept its encoding
+pub fn decompressible(resp: &ResponseHeader) -> bool {
+    resp.headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.trim().eq_ignore_ascii_case(Encoding::Brotli.as_str()))
+}
+
+/// Whether the response is one that should be compressed
+pub fn compressible(config: &CompressionConfig, resp: &ResponseHeader) -> bool {
+    let header = |name| resp.headers.get(name).and_then(|x| x.to_str().ok());
+
+    if header(header::CONTENT_ENCODING).is_some() {
+        return false;
+    }
+
+    if header(header::CONTENT_LENGTH)
+        .and_then(|x| x.trim().parse::<usize>().ok())
+        .is_some_and(|x| x < config.min_size)
+    {
+        return false;
+    }
+
+    header(header::CONTENT_TYPE).is_some_and(|x| config.compresses_type(x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(algorithms: &[Encoding]) -> CompressionConfig {
+        CompressionConfig {
+            algorithms: algorithms.to_vec(),
+            gzip_level: 6,
+            brotli_level: 4,
+            zstd_level: 3,
+            min_size: 1024,
+            content_types: vec!["text/*".to_string(), "application/json".to_string()],
+        }
+    }
+
+    fn negotiated(algorithms: &[Encoding], accept_encoding: &str) -> Option<Encoding> {
+        negotiate(
+            &config(algorithms),
+            Some(&HeaderValue::from_str(accept_encoding).expect("valid header")),
+        )
+    }
+
+    fn response(pairs: &[(&str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).expect("valid response");
+        for (name, value) in pairs {
+            resp.append_header((*name).to_string(), *value)
+                .expect("valid header");
+        }
+        resp
+    }
+
+    #[test]
+    fn negotiates_the_most_preferred_configured_encoding() {
+        let all = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+        assert_eq!(
+            negotiated(&all, "gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(negotiated(&all, "gzip, zstd"), Some(Encoding::Zstd));
+        assert_eq!(
+            negotiated(&[Encoding::Gzip], "br, zstd, gzip"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiated(&all, "GZIP"), Some(Encoding::Gzip));
+        assert_eq!(negotiated(&all, "deflate"), None);
+        assert_eq!(negotiated(&all, ""), None);
+        assert_eq!(negotiate(&config(&all), None), None);
+    }
+
+    #[test]
+    fn refused_encodings_are_not_negotiated() {
+        let all = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+        assert_eq!(negotiated(&all, "zstd;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiated(&all, "br; q=0.0, gzip;q=0.5"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiated(&all, "br;q=0.1"), Some(Encoding::Brotli));
+        assert_eq!(negotiated(&all, "gzip;q=0"), None);
+    }
+
+    #[test]
+    fn compresses_allowed_types_that_are_large_enough() {
+        let config = config(&[Encoding::Gzip]);
+
+        assert!(compressible(
+            &config,
+            &response(&[("content-type", "text/html; charset=utf-8")])
+        ));
+        assert!(compressible(
+            &config,
+            &response(&[
+                ("content-type", "application/json"),
+                ("content-length", "1024")
+            ])
+        ));
+
+        assert!(!compressible(
+            &config,
+            &response(&[
+                ("content-type", "application/json"),
+                ("content-length", "1023")
+            ])
+        ));
+        assert!(!compressible(
+            &config,
+            &response(&[("content-type", "image/png")])
+        ));
+        assert!(!compressible(&config, &response(&[])));
+        assert!(!compressible(
+            &config,
+            &response(&[("content-type", "text/plain"), ("content-encoding", "gzip")])
+        ));
+    }
+
+    #[test]
+    fn only_brotli_is_decompressible() {
+        assert!(decompressible(&response(&[("content-encoding", "br")])));
+        assert!(decompressible(&response(&[("content-encoding", " BR ")])));
+
+        assert!(!decompressible(&response(&[("content-encoding", "gzip")])));
+        assert!(!decompressible(&response(&[])));
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::common::concurrency::ConcurrencyLimitConfig;
+
+/// Caps how many requests are in flight at once, with a bounded queue for the rest
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    config: ConcurrencyLimitConfig,
+    semaphore: Arc<Semaphore>,
+    queued: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    QueueFull,
+    QueueTimeout,
+}
+impl Rejection {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::QueueFull => "queue_full",
+            Self::QueueTimeout => "queue_timeout",
+        }
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: ConcurrencyLimitConfig) -> Self {
+        let semaphore = Arc::new(Semaphore::new(config.max_requests));
+
+        Self {
+            config,
+            semaphore,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for a free slot.
+    ///
+    /// The slot is held until the returned permit is dropped.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, Rejection> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        let _queued =
+            QueueSlot::take(&self.queued, self.config.max_queued).ok_or(Rejection::QueueFull)?;
+
+        match tokio::time::timeout(
+            self.config.queue_timeout,
+            self.semaphore.clone().acquire_owned(),
+        )
+        .await
+        {
+            Ok(Ok(permit)) => Ok(permit),
+            // The semaphore is never closed
+            Ok(Err(_)) => Err(Rejection::QueueFull),
+            Err(_) => Err(Rejection::QueueTimeout),
+        }
+    }
+}
+
+/// A place in the queue, given up when dropped so requests cancelled while waiting don't leak it
+struct QueueSlot<'a>(&'a AtomicUsize);
+impl<'a> QueueSlot<'a> {
+    fn take(queued: &'a AtomicUsize, max: usize) -> Option<Self> {
+        queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                (x < max).then_some(x + 1)
+            })
+            .ok()
+            .map(|_| Self(queued))
+    }
+}
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
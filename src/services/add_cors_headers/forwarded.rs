@@ -0,0 +1,431 @@
+use std::{
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr},
+};
+
+use http::{header, HeaderMap, HeaderName};
+use ipnet::IpNet;
+use pingora::prelude::*;
+
+use crate::config::common::proxy::ForwardedHeaders;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PORT: HeaderName = HeaderName::from_static("x-forwarded-port");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// The listener only accepts plain HTTP
+const PROTO: &str = "http";
+
+/// Whether an address is one of the trusted proxies
+pub fn is_trusted(addr: Option<IpAddr>, trusted_proxies: &[IpNet]) -> bool {
+    addr.is_some_and(|addr| trusted_proxies.iter().any(|net| net.contains(&addr)))
+}
+
+/// Find the address of the client that made a request.
+///
+/// The peer of the connection is the client, unless it's a trusted proxy.
+/// Then the `Forwarded` (or `X-Forwarded-For`) chain is walked from the nearest hop outwards,
+/// and the client is the first hop that isn't a trusted proxy.
+pub fn client_addr(
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let mut client = peer?;
+    if !is_trusted(Some(client), trusted_proxies) {
+        return Some(client);
+    }
+
+    let chain = forwarded_for(headers).unwrap_or_else(|| x_forwarded_for(headers));
+
+    for hop in chain.into_iter().rev() {
+        // Obfuscated or unknown hops can't be checked, so nothing beyond them is trusted
+        let Some(hop) = hop else {
+            break;
+        };
+
+        client = hop;
+
+        if !is_trusted(Some(hop), trusted_proxies) {
+            break;
+        }
+    }
+
+    Some(client)
+}
+
+/// Set the forwarding headers on a request going upstream.
+///
+/// Incoming forwarding headers are extended if the peer is a trusted proxy, and dropped otherwise.
+/// The peer address is added to `Forwarded` and `X-Forwarded-For`,
+/// so with the incoming headers dropped it's the only address in them.
+pub fn set_headers(
+    req: &mut RequestHeader,
+    mode: ForwardedHeaders,
+    peer: Option<IpAddr>,
+    trusted: bool,
+    host: Option<&str>,
+) -> Result<()> {
+    if !trusted {
+        remove_headers(req);
+    }
+
+    if mode.forwarded() {
+        let mut element = format!("for={};proto={PROTO}", forwarded_node(peer));
+        if let Some(host) = host {
+            let _ = write!(element, ";host={}", quote(host));
+        }
+
+        let forwarded = append(&req.headers, &header::FORWARDED, &element);
+        req.insert_header(header::FORWARDED, forwarded)?;
+    }
+
+    if mode.x_forwarded() {
+        if let Some(peer) = peer {
+            let forwarded_for = append(&req.headers, &X_FORWARDED_FOR, &peer.to_string());
+            req.insert_header(X_FORWARDED_FOR, forwarded_for)?;
+        }
+
+        if !req.headers.contains_key(X_FORWARDED_PROTO) {
+            req.insert_header(X_FORWARDED_PROTO, PROTO)?;
+        }
+
+        if let Some(host) = host {
+            if !req.headers.contains_key(X_FORWARDED_HOST) {
+                req.insert_header(X_FORWARDED_HOST, host)?;
+            }
+
+            if !req.headers.contains_key(X_FORWARDED_PORT) {
+                req.insert_header(X_FORWARDED_PORT, host_port(host).to_string())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove all forwarding headers from a request going upstream
+pub fn remove_headers(req: &mut RequestHeader) {
+    for name in [
+        header::FORWARDED,
+        X_FORWARDED_FOR,
+        X_FORWARDED_PROTO,
+        X_FORWARDED_HOST,
+        X_FORWARDED_PORT,
+        X_REAL_IP,
+    ] {
+        req.remove_header(&name);
+    }
+}
+
+/// Join the values of a list header with a new element
+fn append(headers: &HeaderMap, name: &HeaderName, element: &str) -> String {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .chain(std::iter::once(element))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Format an address as a `Forwarded` node, where IPv6 addresses are bracketed and quoted
+fn forwarded_node(addr: Option<IpAddr>) -> String {
+    match addr {
+        Some(IpAddr::V4(addr)) => addr.to_string(),
+        Some(IpAddr::V6(addr)) => format!(r#""[{addr}]""#),
+        None => "unknown".to_string(),
+    }
+}
+
+/// The `for=` parameters of the `Forwarded` headers, or `None` if there are no such headers
+fn forwarded_for(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let values = headers.get_all(header::FORWARDED);
+    values.iter().next()?;
+
+    let chain = values
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect();
+
+    Some(chain)
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| parse_node(x.trim()))
+        .collect()
+}
+
+/// Parses a node as it appears in forwarding headers, eg. `192.0.2.1`, `[2001:db8::1]:4711`
+fn parse_node(s: &str) -> Option<IpAddr> {
+    s.parse::<IpAddr>()
+        .ok()
+        .or_else(|| s.parse::<SocketAddr>().ok().map(|x| x.ip()))
+        .or_else(|| {
+            s.strip_prefix('[')
+                .and_then(|x| x.strip_suffix(']'))
+                .and_then(|x| x.parse().ok())
+        })
+}
+
+fn quote(s: &str) -> String {
+    format!(r#""{}""#, s.replace('\\', r"\\").replace('"', r#"\""#))
+}
+
+fn host_port(host: &str) -> u16 {
+    host.rsplit_once(':')
+        .filter(|(_, port)| !port.ends_with(']'))
+        .and_then(|(_, port)| port.parse().ok())
+        .unwrap_or(80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).expect("valid header name"),
+                value.parse().expect("valid header value"),
+            );
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().expect("valid address")
+    }
+
+    fn nets(s: &[&str]) -> Vec<IpNet> {
+        s.iter()
+            .map(|x| x.parse().expect("valid network"))
+            .collect()
+    }
+
+    fn request(pairs: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).expect("valid request");
+        for (name, value) in pairs {
+            req.append_header((*name).to_string(), *value)
+                .expect("valid header");
+        }
+        req
+    }
+
+    fn values(req: &RequestHeader, name: &str) -> Vec<String> {
+        req.headers
+            .get_all(name)
+            .iter()
+            .map(|x| x.to_str().expect("visible ASCII").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let headers = headers(&[("x-forwarded-for", "192.0.2.1")]);
+
+        assert_eq!(
+            client_addr(&headers, Some(ip("198.51.100.7")), &[]),
+            Some(ip("198.51.100.7"))
+        );
+        assert_eq!(
+            client_addr(&headers, Some(ip("198.51.100.7")), &nets(&["10.0.0.0/8"])),
+            Some(ip("198.51.100.7"))
+        );
+    }
+
+    #[test]
+    fn unknown_peer_is_unknown_client() {
+        let headers = headers(&[("x-forwarded-for", "192.0.2.1")]);
+
+        assert_eq!(client_addr(&headers, None, &nets(&["10.0.0.0/8"])), None);
+    }
+
+    #[test]
+    fn trusted_hops_are_skipped_from_the_right() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        let headers = headers(&[("x-forwarded-for", "203.0.113.9, 192.0.2.1, 10.0.0.2")]);
+
+        assert_eq!(
+            client_addr(&headers, Some(ip("10.0.0.1")), &trusted),
+            Some(ip("192.0.2.1"))
+        );
+    }
+
+    #[test]
+    fn spoofed_hops_beyond_the_client_are_ignored() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        // A client claiming to be a trusted proxy itself is still stopped at
+        let headers = headers(&[("x-forwarded-for", "10.0.0.5, 192.0.2.1")]);
+
+        assert_eq!(
+            client_addr(&headers, Some(ip("10.0.0.1")), &trusted),
+            Some(ip("192.0.2.1"))
+        );
+    }
+
+    #[test]
+    fn xff_split_over_several_headers() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        let headers = headers(&[
+            ("x-forwarded-for", "192.0.2.1"),
+            ("x-forwarded-for", "10.0.0.3"),
+        ]);
+
+        assert_eq!(
+            client_addr(&headers, Some(ip("10.0.0.1")), &trusted),
+            Some(ip("192.0.2.1"))
+        );
+    }
+
+    #[test]
+    fn forwarded_is_preferred_over_xff() {
+        let trusted = nets(&["10.0.0.0/8", "2001:db8:ffff::/48"]);
+        let headers = headers(&[
+            ("x-forwarded-for", "203.0.113.9"),
+            (
+                "forwarded",
+                r#"for="[2001:db8::1]:4711";proto=https, for=2001:db8:ffff::2"#,
+            ),
+        ]);
+
+        assert_eq!(
+            client_addr(&headers, Some(ip("10.0.0.1")), &trusted),
+            Some(ip("2001:db8::1"))
+        );
+    }
+
+    #[test]
+    fn unknown_hop_stops_the_walk() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        let headers = headers(&[("forwarded", "for=192.0.2.1, for=_hidden, for=10.0.0.2")]);
+
+        assert_eq!(
+            client_addr(&headers, Some(ip("10.0.0.1")), &trusted),
+            Some(ip("10.0.0.2"))
+        );
+    }
+
+    #[test]
+    fn all_trusted_chain_gives_the_furthest_hop() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        let headers = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+
+        assert_eq!(
+            client_addr(&headers, Some(ip("10.0.0.1")), &trusted),
+            Some(ip("10.0.0.3"))
+        );
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node("192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("192.0.2.1:8080"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]:4711"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_obfuscated"), None);
+    }
+
+    #[test]
+    fn untrusted_headers_are_replaced() {
+        let mut req = request(&[
+            ("x-forwarded-for", "203.0.113.9"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "evil.example"),
+            ("x-real-ip", "203.0.113.9"),
+            ("forwarded", "for=203.0.113.9"),
+        ]);
+
+        set_headers(
+            &mut req,
+            ForwardedHeaders::All,
+            Some(ip("198.51.100.7")),
+            false,
+            Some("example.com"),
+        )
+        .expect("headers set");
+
+        assert_eq!(values(&req, "x-forwarded-for"), ["198.51.100.7"]);
+        assert_eq!(values(&req, "x-forwarded-proto"), ["http"]);
+        assert_eq!(values(&req, "x-forwarded-host"), ["example.com"]);
+        assert_eq!(values(&req, "x-forwarded-port"), ["80"]);
+        assert!(values(&req, "x-real-ip").is_empty());
+        assert_eq!(
+            values(&req, "forwarded"),
+            [r#"for=198.51.100.7;proto=http;host="example.com""#]
+        );
+    }
+
+    #[test]
+    fn trusted_headers_are_extended() {
+        let mut req = request(&[
+            ("x-forwarded-for", "192.0.2.1"),
+            ("x-forwarded-proto", "https"),
+            ("forwarded", "for=192.0.2.1;proto=https"),
+        ]);
+
+        set_headers(
+            &mut req,
+            ForwardedHeaders::All,
+            Some(ip("2001:db8::2")),
+            true,
+            None,
+        )
+        .expect("headers set");
+
+        assert_eq!(values(&req, "x-forwarded-for"), ["192.0.2.1, 2001:db8::2"]);
+        assert_eq!(values(&req, "x-forwarded-proto"), ["https"]);
+        assert_eq!(
+            values(&req, "forwarded"),
+            [r#"for=192.0.2.1;proto=https, for="[2001:db8::2]";proto=http"#]
+        );
+    }
+
+    #[test]
+    fn unknown_peer_is_reported_as_unknown() {
+        let mut req = request(&[]);
+
+        set_headers(&mut req, ForwardedHeaders::All, None, false, None).expect("headers set");
+
+        assert!(values(&req, "x-forwarded-for").is_empty());
+        assert_eq!(values(&req, "forwarded"), ["for=unknown;proto=http"]);
+    }
+
+    #[test]
+    fn removes_all_headers() {
+        let mut req = request(&[
+            ("forwarded", "for=192.0.2.1"),
+            ("x-forwarded-for", "192.0.2.1"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.com"),
+            ("x-forwarded-port", "443"),
+            ("x-real-ip", "192.0.2.1"),
+            ("accept", "*/*"),
+        ]);
+
+        remove_headers(&mut req);
+
+        assert_eq!(req.headers.len(), 1);
+        assert_eq!(values(&req, "accept"), ["*/*"]);
+    }
+}
@@ -0,0 +1,375 @@
+use std::sync::LazyLock;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{header, HeaderMap};
+use pingora::{
+    connectors::http::Connector,
+    http::{RequestHeader, ResponseHeader},
+    prelude::*,
+};
+
+/// Headers gRPC-Web clients read the status of a call from
+const STATUS_HEADERS: [&str; 3] = ["grpc-status", "grpc-message", "grpc-status-details-bin"];
+
+/// Marks a gRPC-Web frame as holding the trailers rather than a message
+const TRAILER_FLAG: u8 = 0x80;
+
+/// Headers that are specific to HTTP/1 connections, which HTTP/2 requests can't have
+const HTTP1_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Connects to upstreams for text requests, which are proxied outside of Pingora
+pub static CONNECTOR: LazyLock<Connector> = LazyLock::new(|| Connector::new(None));
+
+/// How the gRPC-Web messages of a request are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// `application/grpc-web`, framed like gRPC
+    Binary,
+    /// `application/grpc-web-text`, base64 encoded
+    Text,
+}
+impl Encoding {
+    const fn content_type(self) -> &'static str {
+        match self {
+            Self::Binary => "application/grpc-web",
+            Self::Text => "application/grpc-web-text",
+        }
+    }
+}
+
+/// The gRPC-Web encoding of a request, if it's a gRPC-Web request
+pub fn encoding(req: &RequestHeader) -> Option<Encoding> {
+    let content_type = req.headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let content_type = content_type.trim().to_lowercase();
+
+    if content_type.starts_with("application/grpc-web-text") {
+        Some(Encoding::Text)
+    } else if content_type.starts_with("application/grpc-web") {
+        Some(Encoding::Binary)
+    } else {
+        None
+    }
+}
+
+/// Turn a gRPC-Web request into a gRPC one.
+///
+/// Binary gRPC-Web messages are framed the same way as gRPC ones, so only the headers change.
+/// The body of text requests is decoded separately, see [`text_upstream_request`].
+pub fn upstream_request(req: &mut RequestHeader, encoding: Encoding) -> Result<()> {
+    if let Some(content_type) = req
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| {
+            // Eg. `application/grpc-web-text+proto` becomes `application/grpc+proto`
+            let x = x.trim().to_lowercase();
+            let suffix = x.get(encoding.content_type().len()..).unwrap_or_default();
+            format!("application/grpc{suffix}")
+        })
+    {
+        req.insert_header(header::CONTENT_TYPE, content_type)?;
+    }
+
+    // gRPC servers require it to know the client understands trailers
+    req.insert_header(header::TE, "trailers")?;
+    req.remove_header("x-grpc-web");
+
+    Ok(())
+}
+
+/// Set the headers of a text request for its decoded body, which is sent in one go
+pub fn text_upstream_request(
+    req: &mut RequestHeader,
+    len: usize,
+    upstream_http2: bool,
+) -> Result<()> {
+    req.insert_header(header::CONTENT_LENGTH, len)?;
+    req.remove_header(&header::TRANSFER_ENCODING);
+
+    // Pingora does this for the requests it proxies itself
+    if upstream_http2 {
+        for name in HTTP1_HEADERS {
+            req.remove_header(name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Turn the headers of a gRPC response into gRPC-Web ones
+pub fn response(
+    resp: &mut ResponseHeader,
+    encoding: Encoding,
+    downstream_http2: bool,
+) -> Result<()> {
+    if let Some(content_type) = resp
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| x.starts_with("application/grpc"))
+        .map(|x| x.replacen("application/grpc", encoding.content_type(), 1))
+    {
+        resp.insert_header(header::CONTENT_TYPE, content_type)?;
+    }
+
+    // The trailers are sent in the body, after the length the upstream gave,
+    // and text bodies are longer than the upstream's anyway
+    if resp.remove_header(&header::CONTENT_LENGTH).is_some() && !downstream_http2 {
+        resp.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+    }
+
+    Ok(())
+}
+
+/// Let browsers read the status of the call, which may only come in the trailers
+pub fn expose_status_headers(resp: &mut ResponseHeader) -> Result<()> {
+    let mut exposed = resp
+        .headers
+        .get(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| {
+            x.split(',')
+                .map(|x| x.trim().to_lowercase())
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    for name in STATUS_HEADERS {
+        if !exposed.iter().any(|x| x == name) {
+            exposed.push(name.to_string());
+        }
+    }
+
+    resp.insert_header(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed.join(", "))
+}
+
+/// The gRPC-Web frame holding the trailers, which is sent at the end of the body
+pub fn trailer_frame(trailers: &HeaderMap) -> Bytes {
+    let mut block = BytesMut::new();
+    for (name, value) in trailers {
+        block.put_slice(name.as_str().as_bytes());
+        block.put_slice(b": ");
+        block.put_slice(value.as_bytes());
+        block.put_slice(b"\r\n");
+    }
+
+    let mut frame = BytesMut::with_capacity(block.len() + 5);
+    frame.put_u8(TRAILER_FLAG);
+    frame.put_u32(u32::try_from(block.len()).unwrap_or(u32::MAX));
+    frame.put_slice(&block);
+
+    frame.freeze()
+}
+
+/// Decode the body of a text request.
+///
+/// Clients may send several base64 chunks, each with its own padding, one after another.
+pub fn decode_text(body: &[u8]) -> Result<Bytes> {
+    let body = body
+        .iter()
+        .copied()
+        .filter(|x| !x.is_ascii_whitespace())
+        .collect::<Vec<_>>();
+
+    let mut decoded = Vec::with_capacity(body.len() / 4 * 3);
+    let mut start = 0;
+    for (i, quantum) in body.chunks(4).enumerate() {
+        let end = (i + 1) * 4;
+        if quantum.contains(&b'=') || end >= body.len() {
+            STANDARD
+                .decode_vec(&body[start..end.min(body.len())], &mut decoded)
+                .or_err(HTTPStatus(400), "Invalid base64 in gRPC-Web text request")?;
+            start = end;
+        }
+    }
+
+    Ok(decoded.into())
+}
+
+/// Encode part of the body of a response to a text request, padded so it can be decoded on its own
+pub fn encode_text(data: &[u8]) -> Bytes {
+    STANDARD.encode(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(pairs: &[(&str, &str)]) -> RequestHeader {
+        let mut req =
+            RequestHeader::build("POST", b"/echo.Echo/Echo", None).expect("valid request");
+        for (name, value) in pairs {
+            req.append_header((*name).to_string(), *value)
+                .expect("valid header");
+        }
+        req
+    }
+
+    fn response(pairs: &[(&str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).expect("valid response");
+        for (name, value) in pairs {
+            resp.append_header((*name).to_string(), *value)
+                .expect("valid header");
+        }
+        resp
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        headers
+            .get(name)
+            .map(|x| x.to_str().expect("visible ASCII"))
+    }
+
+    #[test]
+    fn encoding_from_content_type() {
+        let encoding = |x| encoding(&request(&[("content-type", x)]));
+
+        assert_eq!(encoding("application/grpc-web"), Some(Encoding::Binary));
+        assert_eq!(
+            encoding("application/grpc-web+proto"),
+            Some(Encoding::Binary)
+        );
+        assert_eq!(encoding("application/grpc-web-text"), Some(Encoding::Text));
+        assert_eq!(
+            encoding("Application/gRPC-Web-Text+proto"),
+            Some(Encoding::Text)
+        );
+        assert_eq!(encoding("application/grpc"), None);
+        assert_eq!(encoding("application/json"), None);
+        assert_eq!(super::encoding(&request(&[])), None);
+    }
+
+    #[test]
+    fn upstream_request_is_grpc() {
+        for (encoding, content_type) in [
+            (Encoding::Binary, "application/grpc-web+proto"),
+            (Encoding::Text, "application/grpc-web-text+proto"),
+        ] {
+            let mut req = request(&[("content-type", content_type), ("x-grpc-web", "1")]);
+            upstream_request(&mut req, encoding).expect("valid headers");
+
+            assert_eq!(
+                header(&req.headers, "content-type"),
+                Some("application/grpc+proto")
+            );
+            assert_eq!(header(&req.headers, "te"), Some("trailers"));
+            assert_eq!(header(&req.headers, "x-grpc-web"), None);
+        }
+
+        let mut req = request(&[("content-type", "application/grpc-web-text")]);
+        upstream_request(&mut req, Encoding::Text).expect("valid headers");
+        assert_eq!(
+            header(&req.headers, "content-type"),
+            Some("application/grpc")
+        );
+    }
+
+    #[test]
+    fn text_upstream_request_has_decoded_length() {
+        let headers = [
+            ("content-length", "8"),
+            ("connection", "keep-alive"),
+            ("transfer-encoding", "chunked"),
+        ];
+
+        let mut req = request(&headers);
+        text_upstream_request(&mut req, 5, true).expect("valid headers");
+        assert_eq!(header(&req.headers, "content-length"), Some("5"));
+        assert_eq!(header(&req.headers, "connection"), None);
+        assert_eq!(header(&req.headers, "transfer-encoding"), None);
+
+        let mut req = request(&headers);
+        text_upstream_request(&mut req, 5, false).expect("valid headers");
+        assert_eq!(header(&req.headers, "content-length"), Some("5"));
+        assert_eq!(header(&req.headers, "connection"), Some("keep-alive"));
+    }
+
+    #[test]
+    fn response_is_grpc_web() {
+        let grpc = [
+            ("content-type", "application/grpc+proto"),
+            ("content-length", "7"),
+        ];
+
+        let mut resp = response(&grpc);
+        super::response(&mut resp, Encoding::Binary, false).expect("valid headers");
+        assert_eq!(
+            header(&resp.headers, "content-type"),
+            Some("application/grpc-web+proto")
+        );
+        assert_eq!(header(&resp.headers, "content-length"), None);
+        assert_eq!(header(&resp.headers, "transfer-encoding"), Some("chunked"));
+
+        let mut resp = response(&grpc);
+        super::response(&mut resp, Encoding::Text, true).expect("valid headers");
+        assert_eq!(
+            header(&resp.headers, "content-type"),
+            Some("application/grpc-web-text+proto")
+        );
+        assert_eq!(header(&resp.headers, "content-length"), None);
+        assert_eq!(header(&resp.headers, "transfer-encoding"), None);
+
+        let mut resp = response(&[("content-type", "text/plain")]);
+        super::response(&mut resp, Encoding::Text, false).expect("valid headers");
+        assert_eq!(header(&resp.headers, "content-type"), Some("text/plain"));
+    }
+
+    #[test]
+    fn trailer_frame_holds_trailers() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().expect("valid header value"));
+        trailers.insert("grpc-message", "OK".parse().expect("valid header value"));
+
+        let block = b"grpc-status: 0\r\ngrpc-message: OK\r\n";
+        let mut expected = vec![TRAILER_FLAG, 0, 0, 0, 34];
+        expected.extend_from_slice(block);
+
+        assert_eq!(block.len(), 34);
+        assert_eq!(trailer_frame(&trailers), expected);
+        assert_eq!(
+            trailer_frame(&HeaderMap::new()),
+            [TRAILER_FLAG, 0, 0, 0, 0][..]
+        );
+    }
+
+    #[test]
+    fn decodes_text() {
+        assert_eq!(
+            decode_text(b"AAAAAAVoZWxsbw==").expect("valid base64"),
+            b"\0\0\0\0\x05hello"[..]
+        );
+        assert_eq!(decode_text(b"").expect("valid base64"), b""[..]);
+        assert_eq!(
+            decode_text(b"aGk=\r\naGk=\n").expect("valid base64"),
+            b"hihi"[..]
+        );
+    }
+
+    #[test]
+    fn decodes_concatenated_text() {
+        let chunks = [&b"\0\0\0\0\x01a"[..], b"\0\0\0\0\x02bc", b"\x80\0\0\0\0"];
+        let encoded = chunks.iter().map(|x| encode_text(x)).collect::<Vec<_>>();
+
+        assert_eq!(
+            decode_text(&encoded.concat()).expect("valid base64"),
+            chunks.concat()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_text() {
+        for body in [&b"aGk"[..], b"a=Gk", b"aGk=a", b"!!!!"] {
+            let e = decode_text(body).expect_err("invalid base64");
+            assert_eq!(e.etype(), &HTTPStatus(400));
+        }
+    }
+}
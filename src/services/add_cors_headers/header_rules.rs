@@ -0,0 +1,117 @@
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    Result,
+};
+use tracing::trace;
+
+use crate::config::common::headers::{HeaderAction, HeaderRule, TemplateVar};
+
+/// The request or response headers rules are applied to
+pub trait Headers {
+    fn append_header(&mut self, name: String, value: String) -> Result<bool>;
+
+    fn insert_header(&mut self, name: String, value: String) -> Result<()>;
+
+    fn remove_header(&mut self, name: &str);
+}
+impl Headers for RequestHeader {
+    fn append_header(&mut self, name: String, value: String) -> Result<bool> {
+        Self::append_header(self, name, value)
+    }
+
+    fn insert_header(&mut self, name: String, value: String) -> Result<()> {
+        Self::insert_header(self, name, value)
+    }
+
+    fn remove_header(&mut self, name: &str) {
+        Self::remove_header(self, name);
+    }
+}
+impl Headers for ResponseHeader {
+    fn append_header(&mut self, name: String, value: String) -> Result<bool> {
+        Self::append_header(self, name, value)
+    }
+
+    fn insert_header(&mut self, name: String, value: String) -> Result<()> {
+        Self::insert_header(self, name, value)
+    }
+
+    fn remove_header(&mut self, name: &str) {
+        Self::remove_header(self, name);
+    }
+}
+
+/// Apply the rules in order, filling in template variables with `var`
+pub fn apply<'a>(
+    headers: &mut impl Headers,
+    rules: impl IntoIterator<Item = &'a HeaderRule>,
+    var: impl Fn(TemplateVar) -> String,
+) -> Result<()> {
+    for rule in rules {
+        trace!(?rule, "Applying header rule");
+
+        match rule.action {
+            HeaderAction::Add => {
+                headers.append_header(rule.name.clone(), rule.value.render(&var))?;
+            }
+            HeaderAction::Set => {
+                headers.insert_header(rule.name.clone(), rule.value.render(&var))?;
+            }
+            HeaderAction::Remove => headers.remove_header(&rule.name),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(s: &[&str]) -> Vec<HeaderRule> {
+        s.iter()
+            .map(|x| HeaderRule::parse_str(x).expect("valid rule"))
+            .collect()
+    }
+
+    fn values(response: &ResponseHeader, name: &str) -> Vec<String> {
+        response
+            .headers
+            .get_all(name)
+            .iter()
+            .map(|x| x.to_str().expect("visible ASCII").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        let mut response = ResponseHeader::build(200, None).expect("valid response");
+        response
+            .append_header("Server", "upstream")
+            .expect("valid header");
+        response
+            .append_header("Vary", "Accept")
+            .expect("valid header");
+        response.append_header("X-Old", "a").expect("valid header");
+
+        let rules = rules(&[
+            "remove:Server",
+            "add:Vary=Origin",
+            "set:X-Old=b",
+            "add:X-Request-Id={request_id}",
+            "set:X-Removed=a",
+            "remove:x-removed",
+        ]);
+        apply(&mut response, &rules, |var| match var {
+            TemplateVar::RequestId => "abc".to_string(),
+            x => panic!("unexpected variable {x:?}"),
+        })
+        .expect("rules apply");
+
+        assert!(values(&response, "server").is_empty());
+        assert_eq!(values(&response, "vary"), ["Accept", "Origin"]);
+        assert_eq!(values(&response, "x-old"), ["b"]);
+        assert_eq!(values(&response, "x-request-id"), ["abc"]);
+        assert!(values(&response, "x-removed").is_empty());
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http::header;
+use pingora::{http::ResponseHeader, protocols::http::error_resp, proxy::Session};
+
+use crate::config::common::rate_limit::{RateLimitConfig, RateLimitKey};
+
+/// Don't bother cleaning up buckets until there are at least this many
+const MIN_CLEANUP_SIZE: usize = 10_000;
+
+/// Token bucket rate limiter with a bucket per client
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// Tokens added to a bucket per second
+    rate: f64,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    next_cleanup: usize,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimitState {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the bucket is full again
+    pub reset: Duration,
+    /// Time until the next request would be allowed
+    pub retry_after: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let rate = f64::from(config.requests) / config.period.as_secs_f64().max(f64::EPSILON);
+
+        Self {
+            config,
+            rate,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                next_cleanup: MIN_CLEANUP_SIZE,
+            }),
+        }
+    }
+
+    /// The bucket the request belongs to, if the request has the configured key
+    pub fn key(&self, session: &Session, client_addr: Option<IpAddr>) -> Option<String> {
+        let header_str = |name| {
+            session
+                .get_header(name)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_string)
+        };
+
+        match &self.config.key {
+            RateLimitKey::ClientIp => client_addr.map(|x| x.to_string()),
+            RateLimitKey::Origin => header_str(&header::ORIGIN),
+            RateLimitKey::Host => header_str(&header::HOST).map(|x| x.to_lowercase()),
+            RateLimitKey::Header(name) => header_str(name),
+        }
+    }
+
+    /// Take a token from the bucket for `key`
+    pub fn check(&self, key: &str) -> RateLimitState {
+        let now = Instant::now();
+        let burst = f64::from(self.config.burst);
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(x) => x,
+            Err(e) => e.into_inner(),
+        };
+
+        if !buckets.buckets.contains_key(key) && buckets.buckets.len() >= buckets.next_cleanup {
+            let rate = self.rate;
+            buckets.buckets.retain(|_, bucket| {
+                now.duration_since(bucket.updated)
+                    .as_secs_f64()
+                    .mul_add(rate, bucket.tokens)
+                    < burst
+            });
+            buckets.next_cleanup = (buckets.buckets.len() * 2).max(MIN_CLEANUP_SIZE);
+        }
+
+        let bucket = buckets
+            .buckets
+            .entry(key.to_string())
+            .or_insert_with(|| Bucket {
+                tokens: burst,
+                updated: now,
+            });
+
+        bucket.tokens = now
+            .duration_since(bucket.updated)
+            .as_secs_f64()
+            .mul_add(self.rate, bucket.tokens)
+            .min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let tokens = bucket.tokens;
+        drop(buckets);
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let remaining = tokens.floor() as u32;
+
+        RateLimitState {
+            allowed,
+            limit: self.config.burst,
+            remaining,
+            reset: Duration::from_secs_f64((burst - tokens) / self.rate),
+            retry_after: Duration::from_secs_f64((1.0 - tokens).max(0.0) / self.rate),
+        }
+    }
+}
+
+impl RateLimitState {
+    /// The `429 Too Many Requests` response for a limited request
+    pub fn response(&self) -> pingora::Result<ResponseHeader> {
+        let mut response = error_resp::gen_error_response(429);
+
+        response.insert_header(
+            header::RETRY_AFTER,
+            whole_secs(self.retry_after).max(1).to_string(),
+        )?;
+        response.insert_header("RateLimit-Limit", self.limit.to_string())?;
+        response.insert_header("RateLimit-Remaining", self.remaining.to_string())?;
+        response.insert_header("RateLimit-Reset", whole_secs(self.reset).to_string())?;
+
+        Ok(response)
+    }
+}
+
+fn whole_secs(d: Duration) -> u64 {
+    d.as_secs() + u64::from(d.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests: u32, period: Duration, burst: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            requests,
+            period,
+            burst,
+            key: RateLimitKey::ClientIp,
+        })
+    }
+
+    /// Move the bucket's last update back, as if `elapsed` had passed
+    fn wait(limiter: &RateLimiter, key: &str, elapsed: Duration) {
+        limiter
+            .buckets
+            .lock()
+            .expect("not poisoned")
+            .buckets
+            .get_mut(key)
+            .expect("bucket exists")
+            .updated -= elapsed;
+    }
+
+    #[test]
+    fn allows_a_burst() {
+        let limiter = limiter(1, Duration::from_secs(1), 3);
+
+        for remaining in [2, 1, 0] {
+            let state = limiter.check("a");
+            assert!(state.allowed);
+            assert_eq!(state.limit, 3);
+            assert_eq!(state.remaining, remaining);
+        }
+
+        let state = limiter.check("a");
+        assert!(!state.allowed);
+        assert_eq!(state.remaining, 0);
+        assert!(state.retry_after > Duration::ZERO);
+        assert!(state.retry_after <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn buckets_are_per_key() {
+        let limiter = limiter(1, Duration::from_secs(60), 1);
+
+        assert!(limiter.check("a").allowed);
+        assert!(!limiter.check("a").allowed);
+        assert!(limiter.check("b").allowed);
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let limiter = limiter(2, Duration::from_secs(1), 2);
+
+        assert!(limiter.check("a").allowed);
+        assert!(limiter.check("a").allowed);
+        assert!(!limiter.check("a").allowed);
+
+        // Two tokens a second, so one is back after half a second
+        wait(&limiter, "a", Duration::from_millis(510));
+        assert!(limiter.check("a").allowed);
+        assert!(!limiter.check("a").allowed);
+    }
+
+    #[test]
+    fn refill_is_capped_at_the_burst() {
+        let limiter = limiter(10, Duration::from_secs(1), 2);
+
+        assert!(limiter.check("a").allowed);
+        wait(&limiter, "a", Duration::from_secs(60));
+
+        assert!(limiter.check("a").allowed);
+        assert!(limiter.check("a").allowed);
+        assert!(!limiter.check("a").allowed);
+    }
+
+    #[test]
+    fn response_headers() {
+        let state = RateLimitState {
+            allowed: false,
+            limit: 5,
+            remaining: 0,
+            reset: Duration::from_millis(4200),
+            retry_after: Duration::from_millis(200),
+        };
+        let response = state.response().expect("valid response");
+        let header = |name| {
+            response
+                .headers
+                .get(name)
+                .expect("header is set")
+                .to_str()
+                .expect("visible ASCII")
+        };
+
+        assert_eq!(response.status, 429);
+        assert_eq!(header("retry-after"), "1");
+        assert_eq!(header("ratelimit-limit"), "5");
+        assert_eq!(header("ratelimit-remaining"), "0");
+        assert_eq!(header("ratelimit-reset"), "5");
+    }
+}
@@ -0,0 +1,175 @@
+use std::{sync::Mutex, time::Duration};
+
+use http::Method;
+use rand::Rng;
+
+use crate::config::common::retry::{RetryCondition, RetryConfig};
+
+/// The most unused retries that are saved up for bursts of failures, and the budget at startup
+const MAX_BUDGET: f64 = 10.0;
+
+/// Decides which failed upstream requests are retried
+#[derive(Debug)]
+pub struct RetryPolicy {
+    config: RetryConfig,
+    /// How many retries can be made before the budget runs out
+    budget: Mutex<f64>,
+}
+impl RetryPolicy {
+    pub const fn new(config: RetryConfig) -> Self {
+        Self {
+            config,
+            budget: Mutex::new(MAX_BUDGET),
+        }
+    }
+
+    /// Add the share of a new request to the retry budget
+    pub fn deposit(&self) {
+        if let Ok(mut budget) = self.budget.lock() {
+            *budget = (*budget + self.config.budget).min(MAX_BUDGET);
+        }
+    }
+
+    /// Whether a failure should be retried, taking the retry out of the budget if so
+    pub fn should_retry(&self, method: &Method, retries: usize, condition: RetryCondition) -> bool {
+        if retries >= self.config.max_retries
+            || !self.config.conditions.contains(&condition)
+            || !(self.config.non_idempotent || is_idempotent(method))
+        {
+            return false;
+        }
+
+        let Ok(mut budget) = self.budget.lock() else {
+            return false;
+        };
+
+        if *budget < 1.0 {
+            return false;
+        }
+
+        *budget -= 1.0;
+
+        true
+    }
+
+    /// How long to wait before the given retry, counting from 1
+    pub fn backoff(&self, retry: usize) -> Duration {
+        self.max_backoff(retry).mul_f64(rand::thread_rng().gen())
+    }
+
+    /// The longest backoff before the given retry, which doubles with each retry
+    fn max_backoff(&self, retry: usize) -> Duration {
+        let exponent = u32::try_from(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+
+        self.config
+            .backoff
+            .saturating_mul(2_u32.saturating_pow(exponent))
+            .min(self.config.max_backoff)
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    [
+        Method::GET,
+        Method::HEAD,
+        Method::OPTIONS,
+        Method::PUT,
+        Method::DELETE,
+        Method::TRACE,
+    ]
+    .contains(method)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(budget: f64) -> RetryPolicy {
+        RetryPolicy::new(RetryConfig {
+            max_retries: 3,
+            conditions: vec![RetryCondition::Connect, RetryCondition::Status(503)],
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            non_idempotent: false,
+            budget,
+        })
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let policy = policy(0.2);
+
+        let schedule = (1..=6).map(|x| policy.max_backoff(x)).collect::<Vec<_>>();
+        assert_eq!(
+            schedule,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+
+        assert_eq!(policy.max_backoff(usize::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_is_jittered_below_the_max() {
+        let policy = policy(0.2);
+
+        for retry in 1..=5 {
+            for _ in 0..20 {
+                assert!(policy.backoff(retry) <= policy.max_backoff(retry));
+            }
+        }
+    }
+
+    #[test]
+    fn only_configured_failures_are_retried() {
+        let policy = policy(0.2);
+
+        assert!(policy.should_retry(&Method::GET, 0, RetryCondition::Connect));
+        assert!(policy.should_retry(&Method::PUT, 2, RetryCondition::Status(503)));
+
+        assert!(!policy.should_retry(&Method::GET, 3, RetryCondition::Connect));
+        assert!(!policy.should_retry(&Method::GET, 0, RetryCondition::Reset));
+        assert!(!policy.should_retry(&Method::GET, 0, RetryCondition::Status(502)));
+        assert!(!policy.should_retry(&Method::POST, 0, RetryCondition::Connect));
+    }
+
+    #[test]
+    fn budget_starts_full_and_runs_out() {
+        let policy = policy(0.2);
+
+        for _ in 0..10 {
+            assert!(policy.should_retry(&Method::GET, 0, RetryCondition::Connect));
+        }
+        assert!(!policy.should_retry(&Method::GET, 0, RetryCondition::Connect));
+    }
+
+    #[test]
+    fn requests_refill_the_budget() {
+        let policy = policy(0.25);
+        while policy.should_retry(&Method::GET, 0, RetryCondition::Connect) {}
+
+        for _ in 0..3 {
+            policy.deposit();
+        }
+        assert!(!policy.should_retry(&Method::GET, 0, RetryCondition::Connect));
+
+        policy.deposit();
+        assert!(policy.should_retry(&Method::GET, 0, RetryCondition::Connect));
+        assert!(!policy.should_retry(&Method::GET, 0, RetryCondition::Connect));
+    }
+
+    #[test]
+    fn budget_is_capped() {
+        let policy = policy(0.5);
+
+        for _ in 0..1000 {
+            policy.deposit();
+        }
+
+        let retries = std::iter::repeat_with(|| {
+            policy.should_retry(&Method::GET, 0, RetryCondition::Connect)
+        })
+        .take_while(|x| *x)
+        .count();
+        assert_eq!(retries, 10);
+    }
+}
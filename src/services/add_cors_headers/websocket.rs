@@ -0,0 +1,11 @@
+use http::header;
+use pingora::prelude::*;
+
+/// Whether the request is a WebSocket handshake
+pub fn is_handshake(session: &Session) -> bool {
+    session.is_upgrade_req()
+        && session
+            .get_header(header::UPGRADE)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(|x| x.trim().eq_ignore_ascii_case("websocket"))
+}
@@ -0,0 +1,187 @@
+use std::{net::SocketAddr, os::fd::BorrowedFd, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use pingora::{apps::ServerApp, protocols::Stream, server::ShutdownWatch};
+use socket2::SockRef;
+use tokio::io::{unix::AsyncFd, Interest};
+use tracing::{debug, info, trace};
+
+use crate::metrics;
+
+mod proxy_protocol;
+
+/// How long a load balancer has to send the PROXY protocol header
+const PROXY_PROTOCOL_TIMEOUT: Duration = Duration::from_secs(10);
+
+tokio::task_local! {
+    static PEER_ADDR: Option<SocketAddr>;
+}
+
+/// The address the connection of the request being handled comes from.
+///
+/// Only known inside [`Connections`].
+pub fn peer_addr() -> Option<SocketAddr> {
+    PEER_ADDR.try_with(|x| *x).ok().flatten()
+}
+
+/// Makes the address of each connection available to the wrapped app through [`peer_addr`].
+///
+/// Pingora doesn't pass the address of the downstream connection on to the proxy logic.
+#[derive(Debug)]
+pub struct Connections<A> {
+    inner: Arc<A>,
+    /// Networks whose connections start with a PROXY protocol header
+    proxy_protocol: Vec<IpNet>,
+    /// How long reused connections may be idle before the client sends its next request
+    keepalive_timeout: Option<Duration>,
+}
+impl<A> Connections<A> {
+    pub const fn new(
+        inner: Arc<A>,
+        proxy_protocol: Vec<IpNet>,
+        keepalive_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner,
+            proxy_protocol,
+            keepalive_timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl<A> ServerApp for Connections<A>
+where
+    A: ServerApp + Send + Sync + 'static,
+{
+    async fn process_new(
+        self: &Arc<Self>,
+        mut stream: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let _connection = OpenConnection::new();
+
+        let peer = socket_peer_addr(&stream);
+        let peer = match proxied_peer_addr(&mut stream, peer, &self.proxy_protocol).await {
+            Ok(x) => x,
+            Err(e) => {
+                info!(?peer, error = e, "Closing connection");
+                return None;
+            }
+        };
+
+        PEER_ADDR
+            .scope(peer, async move {
+                // Reused connections are handled here rather than handed back,
+                // so every request on them is in scope.
+                // HTTP/2 streams are handled in tasks of their own, but the listener is plain HTTP/1.
+                let mut stream = stream;
+                while let Some(reused) = self.inner.process_new(stream, shutdown).await {
+                    if !next_request(&reused, self.keepalive_timeout, shutdown).await {
+                        break;
+                    }
+
+                    stream = reused;
+                }
+            })
+            .await;
+
+        None
+    }
+
+    fn cleanup(&self) {
+        self.inner.cleanup();
+    }
+}
+
+/// Counts the connection in the active connections metric while it's open
+struct OpenConnection;
+impl OpenConnection {
+    fn new() -> Self {
+        metrics::ACTIVE_CONNECTIONS.inc();
+        Self
+    }
+}
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        metrics::ACTIVE_CONNECTIONS.dec();
+    }
+}
+
+/// The address of the connection, which a load balancer may report with the PROXY protocol.
+///
+/// Returns an error if the connection should be closed.
+async fn proxied_peer_addr(
+    stream: &mut Stream,
+    peer: Option<SocketAddr>,
+    proxy_protocol: &[IpNet],
+) -> Result<Option<SocketAddr>, String> {
+    if !peer.is_some_and(|x| proxy_protocol.iter().any(|net| net.contains(&x.ip()))) {
+        return Ok(peer);
+    }
+
+    let source = tokio::time::timeout(PROXY_PROTOCOL_TIMEOUT, proxy_protocol::read_header(stream))
+        .await
+        .map_err(|_| "timed out waiting for PROXY protocol header".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    trace!(?peer, ?source, "Read PROXY protocol header");
+
+    // The load balancer's own connections (eg. health checks) have no source
+    Ok(source.map(canonical).or(peer))
+}
+
+/// Waits for the client to send its next request on a reused connection.
+///
+/// Returns `false` if it stays idle for longer than the timeout or the server shuts down.
+/// Pingora doesn't time out idle connections it reuses, so this is done before handing them back.
+async fn next_request(
+    stream: &Stream,
+    timeout: Option<Duration>,
+    shutdown: &ShutdownWatch,
+) -> bool {
+    let Some(timeout) = timeout else {
+        return true;
+    };
+
+    // SAFETY: the ID of a stream is the file descriptor of its socket, which stays open while the stream is borrowed
+    let fd = unsafe { BorrowedFd::borrow_raw(stream.id()) };
+
+    // The socket itself is registered with the runtime already, so a duplicate of it is waited on.
+    // If that fails, Pingora waits for the request as usual.
+    let Ok(socket) = SockRef::from(&fd)
+        .try_clone()
+        .and_then(|x| AsyncFd::with_interest(x, Interest::READABLE))
+    else {
+        return true;
+    };
+
+    let mut shutdown = shutdown.clone();
+    tokio::select! {
+        readable = tokio::time::timeout(timeout, socket.readable()) => {
+            if readable.is_err() {
+                debug!(?timeout, "Closing idle connection");
+            }
+
+            readable.is_ok()
+        }
+        Ok(()) = shutdown.changed() => false,
+    }
+}
+
+fn socket_peer_addr(stream: &Stream) -> Option<SocketAddr> {
+    // SAFETY: the ID of a stream is the file descriptor of its socket, which stays open while the stream is borrowed
+    let fd = unsafe { BorrowedFd::borrow_raw(stream.id()) };
+
+    SockRef::from(&fd)
+        .peer_addr()
+        .ok()?
+        .as_socket()
+        .map(canonical)
+}
+
+/// IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+const fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
@@ -0,0 +1,296 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// What a version 2 header starts with
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// What a version 1 header starts with
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// The longest a version 1 header can be, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Read the [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+/// header a load balancer sent at the start of a connection.
+///
+/// Returns the source address of the proxied connection,
+/// or `None` if the proxy didn't pass one on (eg. for its own health checks).
+/// Exactly the header is read, so the rest of the connection can be read as usual.
+pub async fn read_header<R>(stream: &mut R) -> io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    // Both versions' headers are at least this long
+    let mut start = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("version 1 header too long"));
+            }
+
+            line.push(stream.read_u8().await?);
+        }
+
+        parse_v1(&line)
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+/// Parses a version 1 header, eg. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|x| x.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("version 1 header isn't ASCII"))?;
+
+    let fields = line.split(' ').collect::<Vec<_>>();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _destination, source_port, _destination_port] =>
+        {
+            let ip = match *protocol {
+                "TCP4" => source.parse::<Ipv4Addr>().map(IpAddr::from),
+                _ => source.parse::<Ipv6Addr>().map(IpAddr::from),
+            }
+            .map_err(|_| invalid("invalid version 1 source address"))?;
+
+            let port = source_port
+                .parse()
+                .map_err(|_| invalid("invalid version 1 source port"))?;
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed version 1 header")),
+    }
+}
+
+/// Reads the rest of a version 2 header, after the signature
+async fn read_v2<R>(stream: &mut R) -> io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await?;
+
+    let mut addrs = vec![0; usize::from(len)];
+    stream.read_exact(&mut addrs).await?;
+
+    parse_v2(version_command, family, &addrs)
+}
+
+/// Parses the fields of a version 2 header that follow the signature
+fn parse_v2(version_command: u8, family: u8, addrs: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection, eg. a health check
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+
+    // The high nibble is the address family, the low one the transport protocol
+    match family >> 4 {
+        // AF_INET: source and destination addresses, then source and destination ports
+        0x1 => {
+            let addrs: &[u8; 12] = addrs
+                .get(..12)
+                .and_then(|x| x.try_into().ok())
+                .ok_or_else(|| invalid("IPv4 addresses too short"))?;
+
+            let ip = Ipv4Addr::from([addrs[0], addrs[1], addrs[2], addrs[3]]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        0x2 => {
+            let addrs: &[u8; 36] = addrs
+                .get(..36)
+                .and_then(|x| x.try_into().ok())
+                .ok_or_else(|| invalid("IPv6 addresses too short"))?;
+
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNSPEC, AF_UNIX: there's no address to report
+        _ => Ok(None),
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {reason}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let addr = read_header(&mut bytes).await;
+        (addr, bytes)
+    }
+
+    fn socket(s: &str) -> SocketAddr {
+        s.parse().expect("valid address")
+    }
+
+    fn v2(version_command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let len = u16::try_from(addrs.len()).expect("short addresses");
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([version_command, family]);
+        header.extend(len.to_be_bytes());
+        header.extend(addrs);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (addr, rest) =
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n").await;
+
+        assert_eq!(addr.expect("valid header"), Some(socket("192.0.2.1:56324")));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (addr, rest) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\nGET").await;
+
+        assert_eq!(addr.expect("valid header"), Some(socket("[2001:db8::1]:56324")));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (addr, rest) =
+            read(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\nGET").await;
+
+        assert_eq!(addr.expect("valid header"), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_invalid() {
+        for header in [
+            &b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1  198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\n",
+        ] {
+            assert!(
+                read(header).await.0.is_err(),
+                "{:?}",
+                String::from_utf8_lossy(header)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_too_long() {
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(200, b'x');
+        header.extend(b"\r\n");
+
+        assert!(read(&header).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_header() {
+        assert!(read(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .0
+            .is_err());
+        assert!(read(b"GET /").await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_inet() {
+        let mut header = v2(
+            0x21,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        header.extend(b"GET");
+
+        let (addr, rest) = read(&header).await;
+
+        assert_eq!(addr.expect("valid header"), Some(socket("192.0.2.1:56324")));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_inet6_with_tlvs() {
+        let source: Ipv6Addr = "2001:db8::1".parse().expect("valid address");
+        let destination: Ipv6Addr = "2001:db8::2".parse().expect("valid address");
+
+        let mut addrs = source.octets().to_vec();
+        addrs.extend(destination.octets());
+        addrs.extend([0xdc, 0x04, 0x01, 0xbb]);
+        // A TLV after the addresses is skipped
+        addrs.extend([0x04, 0x00, 0x01, 0x00]);
+
+        let mut header = v2(0x21, 0x21, &addrs);
+        header.extend(b"GET");
+
+        let (addr, rest) = read(&header).await;
+
+        assert_eq!(addr.expect("valid header"), Some(socket("[2001:db8::1]:56324")));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let mut header = v2(0x20, 0x00, &[]);
+        header.extend(b"GET");
+
+        let (addr, rest) = read(&header).await;
+
+        assert_eq!(addr.expect("valid header"), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_invalid() {
+        for header in [
+            // Version 1 in a version 2 header
+            v2(0x11, 0x11, &[0; 12]),
+            // Unknown command
+            v2(0x22, 0x11, &[0; 12]),
+            // Addresses too short for the family
+            v2(0x21, 0x11, &[0; 8]),
+            v2(0x21, 0x21, &[0; 12]),
+        ] {
+            assert!(read(&header).await.0.is_err(), "{header:?}");
+        }
+
+        // Shorter than the length says
+        let mut header = v2(0x21, 0x11, &[0; 12]);
+        header.truncate(20);
+        assert!(read(&header).await.0.is_err());
+    }
+}
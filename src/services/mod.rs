@@ -1 +1,3 @@
 pub mod add_cors_headers;
+pub mod connections;
+pub mod resolve_upstreams;
@@ -0,0 +1,176 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use crate::config::common::{denylist::Denylist, proxy::ProxyConfig, upstream::Upstream};
+
+/// An upstream and the addresses it last resolved to
+#[derive(Debug)]
+pub struct ResolvedUpstream {
+    upstream: Upstream,
+    addrs: RwLock<Vec<SocketAddr>>,
+}
+impl ResolvedUpstream {
+    fn new(upstream: &Upstream) -> Arc<Self> {
+        Arc::new(Self {
+            upstream: upstream.clone(),
+            addrs: RwLock::new(upstream.addrs.clone()),
+        })
+    }
+
+    /// The addresses to connect to, in order of preference.
+    ///
+    /// Retries go through the addresses in turn, so they reach a different one if there are several.
+    pub fn addrs(&self, retry: usize) -> Vec<SocketAddr> {
+        let mut addrs = self
+            .addrs
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+
+        if addrs.is_empty() {
+            return self.upstream.addrs.clone();
+        }
+
+        let len = addrs.len();
+        addrs.rotate_left(retry % len);
+
+        addrs
+    }
+
+    /// Resolve the host name again, keeping the previous addresses if that fails
+    /// or every new address is in the deny-list
+    async fn resolve(&self, denylist: Option<&Denylist>) {
+        let host = &self.upstream.host;
+
+        let resolved = match tokio::net::lookup_host((host.as_str(), self.upstream.port)).await {
+            Ok(x) => x.collect::<Vec<_>>(),
+            Err(e) => {
+                warn!(
+                    ?host,
+                    ?e,
+                    "Failed to resolve upstream, keeping its previous addresses"
+                );
+                return;
+            }
+        };
+
+        let (mut allowed, denied): (Vec<_>, Vec<_>) = resolved
+            .into_iter()
+            .partition(|addr| denylist.is_none_or(|x| !x.denies(addr.ip())));
+
+        if !denied.is_empty() {
+            warn!(
+                ?host,
+                ?denied,
+                "Upstream resolved to addresses in the deny-list"
+            );
+        }
+
+        if allowed.is_empty() {
+            warn!(
+                ?host,
+                "Upstream has no allowed addresses, keeping its previous ones"
+            );
+            return;
+        }
+
+        // DNS servers often rotate the order of the records
+        allowed.sort_unstable();
+        allowed.dedup();
+
+        let mut addrs = self.addrs.write().unwrap_or_else(PoisonError::into_inner);
+        if *addrs == allowed {
+            debug!(?host, addrs = ?allowed, "Upstream addresses unchanged");
+        } else {
+            info!(?host, old = ?*addrs, new = ?allowed, "Upstream addresses changed");
+            *addrs = allowed;
+        }
+    }
+}
+
+/// The resolved addresses of the default upstream and the upstreams of routes
+#[derive(Debug, Clone)]
+pub struct UpstreamAddrs {
+    default: Arc<ResolvedUpstream>,
+    /// By route index, `None` for routes that use the default upstream
+    routes: Vec<Option<Arc<ResolvedUpstream>>>,
+}
+impl UpstreamAddrs {
+    pub fn new(config: &ProxyConfig) -> Self {
+        Self {
+            default: ResolvedUpstream::new(&config.proxy_to),
+            routes: config
+                .routes
+                .iter()
+                .map(|x| x.proxy_to.as_ref().map(ResolvedUpstream::new))
+                .collect(),
+        }
+    }
+
+    /// The upstream of a route, or the default one for `None`
+    pub fn get(&self, route: Option<usize>) -> &ResolvedUpstream {
+        route
+            .and_then(|x| self.routes.get(x)?.as_deref())
+            .unwrap_or(&self.default)
+    }
+
+    /// The upstreams given by host name
+    fn named(&self) -> impl Iterator<Item = &ResolvedUpstream> {
+        std::iter::once(&self.default)
+            .chain(self.routes.iter().flatten())
+            .map(AsRef::as_ref)
+            .filter(|x| !x.upstream.is_ip())
+    }
+}
+
+/// Resolves the upstreams given by host name again on an interval
+#[derive(Debug)]
+pub struct UpstreamResolver {
+    addrs: UpstreamAddrs,
+    interval: Duration,
+    denylist: Option<Denylist>,
+}
+impl UpstreamResolver {
+    /// `None` if re-resolution is turned off or there are no host names to resolve
+    pub fn new(addrs: UpstreamAddrs, config: &ProxyConfig) -> Option<Self> {
+        let interval = config.upstream_resolve_interval?;
+
+        addrs.named().next()?;
+
+        Some(Self {
+            addrs,
+            interval,
+            denylist: config.upstream_denylist.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl BackgroundService for UpstreamResolver {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // The first tick completes immediately, and the upstreams were just resolved at startup
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => {}
+            }
+
+            for upstream in self.addrs.named() {
+                upstream.resolve(self.denylist.as_ref()).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+};
+
+use async_trait::async_trait;
+use http::HeaderMap;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{TraceError, TraceResult, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    export::trace::SpanData,
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{BatchSpanProcessor, Span, SpanProcessor, Tracer, TracerProvider},
+    Resource,
+};
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use tracing::{info, warn};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::Registry;
+
+use crate::config::common::telemetry::{OtlpProtocol, TelemetryConfig};
+
+/// Set up trace context propagation and create the layer that gives spans their trace context.
+///
+/// Spans get trace context that is propagated upstream whether or not there is a collector.
+/// They're only exported once the returned exporter is started: it runs on a pingora runtime,
+/// so it's only created after the server has daemonized.
+pub fn init(config: &TelemetryConfig) -> (OpenTelemetryLayer<Registry, Tracer>, Option<Exporter>) {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let batch = Arc::new(OnceLock::new());
+    let provider = TracerProvider::builder()
+        .with_span_processor(DeferredProcessor(batch.clone()))
+        .with_resource(resource(config))
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")));
+
+    let exporter = config.otlp_endpoint.as_ref().map(|endpoint| Exporter {
+        config: config.clone(),
+        endpoint: endpoint.clone(),
+        batch,
+    });
+
+    (layer, exporter)
+}
+
+/// Exports spans to the configured OTLP collector until the server shuts down
+pub struct Exporter {
+    config: TelemetryConfig,
+    endpoint: String,
+    batch: Arc<OnceLock<BatchSpanProcessor<runtime::Tokio>>>,
+}
+
+#[async_trait]
+impl BackgroundService for Exporter {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let exporter = match span_exporter(&self.config, &self.endpoint) {
+            Ok(exporter) => exporter,
+            Err(e) => {
+                warn!(endpoint = ?self.endpoint, error = ?e, "Failed to create OTLP exporter");
+                return;
+            }
+        };
+
+        let mut batch = BatchSpanProcessor::builder(exporter, runtime::Tokio).build();
+        batch.set_resource(&resource(&self.config));
+        if self.batch.set(batch).is_err() {
+            warn!("OTLP exporter already started");
+            return;
+        }
+
+        info!(endpoint = ?self.endpoint, "Exporting spans");
+
+        let _ = shutdown.changed().await;
+
+        // Flushing blocks until the batch task, which runs on this runtime, has exported
+        let batch = self.batch.clone();
+        let _ = tokio::task::spawn_blocking(move || batch.get().map(SpanProcessor::shutdown)).await;
+    }
+}
+
+/// Hands finished spans to the batch exporter once there is one, and drops them until then
+#[derive(Debug)]
+struct DeferredProcessor(Arc<OnceLock<BatchSpanProcessor<runtime::Tokio>>>);
+impl SpanProcessor for DeferredProcessor {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        if let Some(batch) = self.0.get() {
+            batch.on_start(span, cx);
+        }
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if let Some(batch) = self.0.get() {
+            batch.on_end(span);
+        }
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        self.0.get().map_or(Ok(()), SpanProcessor::force_flush)
+    }
+
+    fn shutdown(&self) -> TraceResult<()> {
+        // The exporter shuts the batch down itself, on its own runtime
+        Ok(())
+    }
+}
+
+fn span_exporter(config: &TelemetryConfig, endpoint: &str) -> Result<SpanExporter, TraceError> {
+    Ok(match config.otlp_protocol {
+        OtlpProtocol::Grpc => SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?,
+        OtlpProtocol::Http => SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?,
+    })
+}
+
+fn resource(config: &TelemetryConfig) -> Resource {
+    Resource::new([KeyValue::new(
+        "service.name",
+        config.otlp_service_name.clone(),
+    )])
+}
+
+/// Extract the W3C trace context (`traceparent`/`tracestate`) from request headers
+pub fn extract_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)))
+}
+
+/// Get the headers that propagate the given trace context to another service
+pub fn inject_context(cx: &Context) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+
+    global::get_text_map_propagator(|p| p.inject_context(cx, &mut headers));
+    headers.retain(|_, value| !value.is_empty());
+
+    headers
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|x| x.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(http::HeaderName::as_str).collect()
+    }
+}